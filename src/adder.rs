@@ -6,6 +6,7 @@ use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Item, Value};
 
 use crate::converter::json_to_toml;
 use crate::field_finder::{get_field, DoInsert, TomlValue};
+use crate::key_path;
 use crate::AddOp;
 
 pub fn handle_add(doc: &mut DocumentMut, op: AddOp) -> Result<()> {
@@ -15,12 +16,8 @@ pub fn handle_add(doc: &mut DocumentMut, op: AddOp) -> Result<()> {
     match op.table_header_path {
         Some(thpath) => {
             let value = op.value.context("error: expected value to add")?;
-            let mut table_header_path_vec = thpath
-                .split('/')
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
-            let mut dotted_path_vec =
-                path.map(|p| p.split('/').map(|s| s.to_string()).collect::<Vec<String>>());
+            let mut table_header_path_vec = key_path::split(&thpath);
+            let mut dotted_path_vec = path.map(|p| key_path::split(&p));
             let field_value_json: JValue =
                 from_str(&value).context("parsing value field in add request")?;
             let field_value_toml: Item = json_to_toml(&field_value_json, true)
@@ -52,11 +49,7 @@ pub fn handle_add(doc: &mut DocumentMut, op: AddOp) -> Result<()> {
             )
         }
         None => {
-            let mut path_split = path
-                .context("Missing 'path' value")?
-                .split('/')
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
+            let mut path_split = key_path::split(&path.context("Missing 'path' value")?);
 
             let last_field = path_split.pop().context("Path is empty")?;
 
@@ -75,26 +68,25 @@ pub fn handle_add(doc: &mut DocumentMut, op: AddOp) -> Result<()> {
             let field_value_toml: Item = json_to_toml(&field_value_json, is_inline)
                 .context("converting value in add request from json to toml")?;
 
-            match final_field_value {
-                TomlValue::Table(table) => {
-                    table.insert(&last_field, field_value_toml);
-                    Ok(())
-                }
-                TomlValue::ArrayOfTables(array) => {
-                    add_in_array_of_tables(array, &last_field, field_value_toml)
-                }
-                TomlValue::Array(array) => add_in_array(array, &last_field, field_value_toml),
-                TomlValue::InlineTable(table) => {
-                    add_in_inline_table(table, &last_field, field_value_toml)
-                }
-                TomlValue::Value(value) => {
-                    add_in_generic_value(value, &last_field, field_value_toml)
-                }
-            }
+            add_in_container(final_field_value, &last_field, field_value_toml)
         }
     }
 }
 
+// inserts an already converted item as `last_field` of the container found by `get_field`
+pub fn add_in_container(container: TomlValue, last_field: &str, toml: Item) -> Result<()> {
+    match container {
+        TomlValue::Table(table) => {
+            table.insert(last_field, toml);
+            Ok(())
+        }
+        TomlValue::ArrayOfTables(array) => add_in_array_of_tables(array, last_field, toml),
+        TomlValue::Array(array) => add_in_array(array, last_field, toml),
+        TomlValue::InlineTable(table) => add_in_inline_table(table, last_field, toml),
+        TomlValue::Value(value) => add_in_generic_value(value, last_field, toml),
+    }
+}
+
 fn add_in_array_of_tables(array: &mut ArrayOfTables, last_field: &str, toml: Item) -> Result<()> {
     let insert_at_index = last_field.parse::<usize>().context("parsing last_field")?;
 
@@ -677,6 +669,57 @@ key = "second"
 "#
     );
 
+    add_table_header_test!(
+        test_add_arrays_of_tables_dotted_path,
+        Some("tool/uv/index/[[]]"),
+        Some("name"),
+        r#"
+        "pytorch-cpu"
+        "#,
+        "",
+        r#"
+[[tool.uv.index]]
+name = "pytorch-cpu"
+"#
+    );
+
+    add_table_header_test!(
+        test_append_arrays_of_tables_dotted_path,
+        Some("tool/uv/index/[[]]"),
+        Some("source/url"),
+        r#"
+        "https://download.pytorch.org"
+        "#,
+        r#"
+[[tool.uv.index]]
+name = "first"
+        "#,
+        r#"
+[[tool.uv.index]]
+name = "first"
+
+[[tool.uv.index]]
+source.url = "https://download.pytorch.org"
+"#
+    );
+
+    add_table_header_error_test!(
+        test_error_append_arrays_of_tables_to_table,
+        Some("tool/uv/index/[[]]"),
+        Some("name"),
+        r#"
+        "second"
+        "#,
+        r#"
+[tool.uv.index]
+name = "first"
+        "#,
+        r#"
+[tool.uv.index]
+name = "first"
+        "#
+    );
+
     add_table_header_test!(
         test_add_table_literal,
         Some("tool/uv/sources"),
@@ -31,6 +31,21 @@ pub fn add_value_with_table_header_and_dotted_path(
             )?;
             Ok(())
         }
+        // "[[]]" appends a new table to the array of tables, which the dotted
+        // path, if any, goes into
+        Some(field) if array_of_tables && table_header_path.len() == 1 => {
+            let new_table = table_of_array(dotted_path, value, append_array_at_path)?;
+            match table.get_mut(field) {
+                None | Some(Item::None) => {
+                    table.insert(field, array());
+                    let aot = table[field].as_array_of_tables_mut().unwrap();
+                    aot.push(new_table);
+                }
+                Some(Item::ArrayOfTables(aot)) => aot.push(new_table),
+                Some(_) => bail!("Expected an array of tables at '{field}'"),
+            }
+            Ok(())
+        }
         Some(field) => match table.get_mut(field) {
             Some(Item::Table(ref mut inner_table)) => {
                 inner_table.set_dotted(table_header_path.len() > 1);
@@ -60,13 +75,7 @@ pub fn add_value_with_table_header_and_dotted_path(
                 } else {
                     match value {
                         Item::Value(Value::InlineTable(it)) => {
-                            if array_of_tables {
-                                table.insert(field, array());
-                                let aot = table[field].as_array_of_tables_mut().unwrap();
-                                aot.push(it.into_table());
-                            } else {
-                                table.insert(field, Item::Table(it.into_table()));
-                            }
+                            table.insert(field, Item::Table(it.into_table()));
                         }
                         other => {
                             bail!("unexpected value: {:?}", other);
@@ -78,25 +87,32 @@ pub fn add_value_with_table_header_and_dotted_path(
             Some(Item::Value(_)) => {
                 bail!("cannot set a key on a non-table")
             }
-            Some(Item::ArrayOfTables(aot)) => {
-                match value {
-                    Item::Value(Value::InlineTable(it)) => {
-                        if array_of_tables {
-                            aot.push(it.into_table());
-                        } else {
-                            bail!("Expected [[]] syntax for appending to an array of tables");
-                        }
-                    }
-                    other => {
-                        bail!("unexpected value: {:?}", other);
-                    }
-                };
-                Ok(())
+            Some(Item::ArrayOfTables(_)) => {
+                bail!("Expected [[]] syntax for appending to an array of tables")
             }
         },
     }
 }
 
+// the table that "[[]]" appends: the value itself, or a table with the value at the dotted path
+fn table_of_array(
+    dotted_path: Option<Vec<String>>,
+    value: Item,
+    append_array_at_path: bool,
+) -> Result<Table> {
+    match dotted_path {
+        Some(dotted_path) => {
+            let mut table = Table::new();
+            add_value_with_dotted_path(&mut table, &dotted_path, value, append_array_at_path)?;
+            Ok(table)
+        }
+        None => match value {
+            Item::Value(Value::InlineTable(it)) => Ok(it.into_table()),
+            other => bail!("unexpected value: {:?}", other),
+        },
+    }
+}
+
 /*
 Perform an "add" at a dotted_path.
 Example:
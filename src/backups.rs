@@ -0,0 +1,113 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value as JValue};
+
+/*
+Backups of a file live next to it in a directory named after it, e.g.
+`.replit.bak/`, with one file per generation named after the time it was
+taken in milliseconds since the epoch. Generation 1 is the most recent one.
+*/
+pub fn dir(file: &Path) -> PathBuf {
+    let mut name = file
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_else(|| OsString::from("toml"));
+    name.push(".bak");
+    file.with_file_name(name)
+}
+
+// Saves the contents the file had before a write and drops the generations
+// beyond the `keep` most recent ones.
+pub fn save(file: &Path, contents: &str, keep: usize) -> Result<()> {
+    let dir = dir(file);
+    fs::create_dir_all(&dir).with_context(|| format!("error: creating backups: {:?}", dir))?;
+
+    let mut timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("reading the clock")?
+        .as_millis();
+    // two writes within the same millisecond still get a generation each
+    while dir.join(timestamp.to_string()).exists() {
+        timestamp += 1;
+    }
+    let backup = dir.join(timestamp.to_string());
+    fs::write(&backup, contents).with_context(|| format!("error: writing backup: {:?}", backup))?;
+
+    for (_, old) in generations(file)?.into_iter().skip(keep) {
+        fs::remove_file(&old).with_context(|| format!("error: removing backup: {:?}", old))?;
+    }
+    Ok(())
+}
+
+// lists the generations as { "generation": 1, "timestamp": 1718000000000 }, most recent first
+pub fn list(file: &Path) -> Result<JValue> {
+    Ok(generations(file)?
+        .iter()
+        .enumerate()
+        .map(|(index, (timestamp, _))| json!({ "generation": index + 1, "timestamp": timestamp }))
+        .collect())
+}
+
+pub fn load(file: &Path, generation: usize) -> Result<String> {
+    let (_, backup) = match generation.checked_sub(1) {
+        Some(index) => generations(file)?.into_iter().nth(index),
+        None => None,
+    }
+    .with_context(|| format!("there is no backup generation {}", generation))?;
+    fs::read_to_string(&backup).with_context(|| format!("error: reading backup: {:?}", backup))
+}
+
+fn generations(file: &Path) -> Result<Vec<(u128, PathBuf)>> {
+    let dir = dir(file);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => bail!("error: reading backups: {:?}: {}", dir, err),
+    };
+
+    // anything that isn't named like a backup was put there by someone else
+    let mut generations = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let timestamp = entry.file_name().to_str()?.parse::<u128>().ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    generations.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod backups_tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn backups_dir_is_next_to_file() {
+        assert_eq!(
+            dir(Path::new("/home/runner/app/.replit")),
+            Path::new("/home/runner/app/.replit.bak")
+        );
+    }
+
+    #[test]
+    fn backups_are_rotated() {
+        let file = scratch_dir("rotate").join(".replit");
+        for contents in ["a = 1", "a = 2", "a = 3"] {
+            save(&file, contents, 2).unwrap();
+        }
+
+        assert_eq!(list(&file).unwrap().as_array().unwrap().len(), 2);
+        assert_eq!(load(&file, 1).unwrap(), "a = 3");
+        assert_eq!(load(&file, 2).unwrap(), "a = 2");
+        assert!(load(&file, 3).is_err());
+        assert!(load(&file, 0).is_err());
+
+        fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+}
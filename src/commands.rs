@@ -0,0 +1,304 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{from_str, json, Value};
+
+use crate::journal::Caller;
+use crate::limits;
+use crate::traversal::{traverse, TraverseOps};
+use crate::{do_edits, edit_content, read_doc, replay, Command, Edited, Session};
+
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_NOT_FOUND: i32 = 2;
+pub const EXIT_CHANGED: i32 = 3;
+
+// what becomes of an edited document
+#[derive(Clone, Copy, PartialEq)]
+pub enum Output {
+    Write,
+    // printed on stdout instead of written, see --return-output
+    Print,
+    // only reported through the exit code, see --check
+    Check,
+}
+
+/*
+The one-shot commands let scripts edit a file without speaking the JSON
+protocol, e.g. `toml-editor set nix/channel '"stable-24_05"'`. Each of them
+is sent through `do_edits` as a batch, so the history, journal, backups,
+policy and limits work the same as for lines read from stdin. `filter` edits
+a document piped through it instead, like content sent with a request.
+
+Returns the exit code: 0 on success, 2 if the path (or something an op
+needed) doesn't exist and 1 for every other error, which is printed on stderr.
+When checking, 3 tells that the document would change and 0 that it wouldn't.
+*/
+pub fn run(command: Command, file: &Path, output: Output, session: &Session) -> i32 {
+    match execute(command, file, output, session) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            exit_code(&err)
+        }
+    }
+}
+
+fn execute(command: Command, file: &Path, output: Output, session: &Session) -> Result<i32> {
+    let edited = match command {
+        Command::Serve { .. } => bail!("serve is not a one-shot command"),
+        Command::Lsp => bail!("lsp is not a one-shot command"),
+        Command::Replay { journal } => {
+            let write = output == Output::Write;
+            let edited = replay(file, &journal, write, &mut io::stderr())?;
+            if output == Output::Print {
+                print!("{}", edited.contents);
+            }
+            edited
+        }
+        Command::Get { path } => {
+            let ops = vec![json!({ "op": "get", "path": path })];
+            match edit(file, ops, Output::Write, session)?.outputs.remove(0) {
+                Value::Null => return Ok(not_found(&path)),
+                value => println!("{}", value),
+            }
+            return Ok(0);
+        }
+        Command::Set { path, value } => {
+            let ops = vec![json!({ "op": "add", "path": path, "value": value })];
+            edit(file, ops, output, session)?
+        }
+        Command::Rm { path } => {
+            // remove succeeds for missing paths, so whether there is anything to remove is looked up first
+            let (_, mut doc) = read_doc(file)?;
+            if !matches!(traverse(TraverseOps::Get, &mut doc, &path), Ok(Some(_))) {
+                return Ok(not_found(&path));
+            }
+            let ops = vec![json!({ "op": "remove", "path": path })];
+            edit(file, ops, output, session)?
+        }
+        Command::Apply { patch } => {
+            let edited = edit(file, read_patch(&patch, session)?, output, session)?;
+            if output != Output::Check {
+                println!("{}", Value::Array(edited.outputs.clone()));
+            }
+            edited
+        }
+        Command::Filter { ops, ops_file } => {
+            let ops = match (ops, ops_file) {
+                (Some(ops), _) => from_str(&ops).context("error: parsing ops")?,
+                (None, Some(ops_file)) => read_patch(&ops_file, session)?,
+                (None, None) => bail!("no ops given"),
+            };
+            session.limits.check_batch(&ops)?;
+            let content = limits::read_all(io::stdin(), session.limits.document_bytes)?;
+            let edited = edit_content(&content, ops, None, session)?;
+            if output != Output::Check {
+                print!("{}", edited.contents);
+            }
+            edited
+        }
+    };
+
+    match (output, edited.changed) {
+        (Output::Check, true) => Ok(EXIT_CHANGED),
+        _ => Ok(0),
+    }
+}
+
+// applies a batch to the file, which is written, printed or only checked
+fn edit(file: &Path, ops: Vec<Value>, output: Output, session: &Session) -> Result<Edited> {
+    session.limits.check_batch(&ops)?;
+    let print = output == Output::Print;
+    let dry_run = output == Output::Check;
+    let edited = do_edits(file, ops, print, dry_run, &Caller::default(), session)?;
+    if print {
+        print!("{}", edited.contents);
+    }
+    Ok(edited)
+}
+
+// a batch of ops as it would be sent on stdin, from a file or `-` for stdin,
+// where it is bounded like a request line
+fn read_patch(patch: &Path, session: &Session) -> Result<Vec<Value>> {
+    let contents = match patch.to_str() {
+        Some("-") => limits::read_all(io::stdin(), session.limits.line_bytes)?,
+        // not finding the patch is an error of its own, not a missing path
+        _ => fs::read_to_string(patch)
+            .map_err(|err| anyhow!("error: reading patch - {:?}: {}", patch, err))?,
+    };
+    from_str(&contents).map_err(|err| anyhow!("error: parsing patch - {:?}: {}", patch, err))
+}
+
+fn not_found(path: &str) -> i32 {
+    eprintln!("error: {} does not exist", path);
+    EXIT_NOT_FOUND
+}
+
+fn exit_code(err: &anyhow::Error) -> i32 {
+    let not_found = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+    });
+    match not_found {
+        true => EXIT_NOT_FOUND,
+        false => EXIT_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod commands_tests {
+    use super::*;
+    use crate::testing::scratch_file;
+
+    #[test]
+    fn commands_exit_codes() {
+        let file = scratch_file("commands", "run = \"python3 main.py\"\nports = [80]\n");
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let run = |command| run(command, &file, Output::Write, &session);
+
+        assert_eq!(
+            run(Command::Get {
+                path: "run".to_string()
+            }),
+            0
+        );
+        assert_eq!(
+            run(Command::Get {
+                path: "entrypoint".to_string()
+            }),
+            EXIT_NOT_FOUND
+        );
+        assert_eq!(
+            run(Command::Set {
+                path: "nix/channel".to_string(),
+                value: "\"stable-24_05\"".to_string(),
+            }),
+            0
+        );
+        assert_eq!(
+            run(Command::Set {
+                path: "nix/channel".to_string(),
+                value: "stable-24_05".to_string(),
+            }),
+            EXIT_ERROR
+        );
+        assert_eq!(
+            run(Command::Rm {
+                path: "ports/0".to_string()
+            }),
+            0
+        );
+        assert_eq!(
+            run(Command::Rm {
+                path: "ports/0".to_string()
+            }),
+            EXIT_NOT_FOUND
+        );
+        assert_eq!(
+            run(Command::Apply {
+                patch: dir.join("missing.json"),
+            }),
+            EXIT_ERROR
+        );
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "run = \"python3 main.py\"\nports = []\n\n[nix]\nchannel = \"stable-24_05\"\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commands_check() {
+        let contents = "[nix]\nchannel = \"stable-24_05\"\n";
+        let file = scratch_file("check", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let check = |value: &str| {
+            let command = Command::Set {
+                path: "nix/channel".to_string(),
+                value: value.to_string(),
+            };
+            run(command, &file, Output::Check, &session)
+        };
+
+        assert_eq!(check("\"stable-24_05\""), 0);
+        assert_eq!(check("\"stable-23_05\""), EXIT_CHANGED);
+        assert_eq!(fs::read_to_string(&file).unwrap(), contents);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commands_replay() {
+        let file = scratch_file("commands-replay", "run = \"python3 main.py\"\n");
+        let dir = file.parent().unwrap().to_path_buf();
+        let journal = dir.join("journal.jsonl");
+        let session = Session {
+            journal: Some(journal.clone()),
+            ..Session::for_test(&dir)
+        };
+        let ops = vec![json!({ "op": "add", "path": "audio", "value": "true" })];
+        edit(&file, ops, Output::Write, &session).unwrap();
+        let edited = fs::read_to_string(&file).unwrap();
+
+        let contents = "run = \"python3 main.py\"\n";
+        fs::write(&file, contents).unwrap();
+        let replay = |output| {
+            let journal = journal.clone();
+            run(Command::Replay { journal }, &file, output, &session)
+        };
+
+        // neither checking nor printing writes the file
+        assert_eq!(replay(Output::Check), EXIT_CHANGED);
+        assert_eq!(replay(Output::Print), 0);
+        assert_eq!(fs::read_to_string(&file).unwrap(), contents);
+
+        assert_eq!(replay(Output::Write), 0);
+        assert_eq!(fs::read_to_string(&file).unwrap(), edited);
+        assert_eq!(replay(Output::Check), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commands_edit_content() {
+        let dir = scratch_file("content", "").parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let ops = vec![json!({ "op": "add", "path": "nix/channel", "value": "\"stable-24_05\"" })];
+
+        let edited =
+            edit_content("run = \"python3 main.py\" # entry\n", ops, None, &session).unwrap();
+        assert!(edited.changed);
+        assert_eq!(
+            edited.contents,
+            "run = \"python3 main.py\" # entry\n\n[nix]\nchannel = \"stable-24_05\"\n"
+        );
+        assert_eq!(
+            edited.inverse,
+            vec![json!({ "op": "remove", "path": "nix" })]
+        );
+        assert!(edit_content("", vec![json!({ "op": "undo" })], None, &session).is_err());
+        assert!(edit_content("", vec![json!({ "op": "backups" })], None, &session).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commands_not_found_errors() {
+        let err = anyhow::Error::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Path does not exist",
+        ))
+        .context("applying op");
+        assert_eq!(exit_code(&err), EXIT_NOT_FOUND);
+        assert_eq!(
+            exit_code(&anyhow!("Key is not a valid integer")),
+            EXIT_ERROR
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value as JValue};
+use toml_edit::DocumentMut;
+
+use crate::key_path;
+use crate::traversal::{traverse, TraverseOps};
+
+/*
+A single step of a patch (https://datatracker.ietf.org/doc/html/rfc6902)
+between two JSON representations of a document. Paths are kept as segments
+and only joined when the patch is handed out.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOp {
+    Add { path: Vec<String>, value: JValue },
+    Remove { path: Vec<String> },
+    Replace { path: Vec<String>, value: JValue },
+}
+
+impl PatchOp {
+    pub fn path(&self) -> &[String] {
+        match self {
+            PatchOp::Add { path, .. }
+            | PatchOp::Remove { path }
+            | PatchOp::Replace { path, .. } => path,
+        }
+    }
+
+    // in the same format as the ops we read, so a patch can be sent back as is
+    pub fn to_json(&self) -> JValue {
+        match self {
+            PatchOp::Add { path, value } => {
+                json!({ "op": "add", "path": key_path::join(path), "value": value.to_string() })
+            }
+            PatchOp::Remove { path } => json!({ "op": "remove", "path": key_path::join(path) }),
+            PatchOp::Replace { path, value } => {
+                json!({ "op": "replace", "path": key_path::join(path), "value": value.to_string() })
+            }
+        }
+    }
+}
+
+/*
+Computes the patch that turns the item at `path` (or the whole document)
+into the same item of `other`, which is the text of another TOML document.
+*/
+pub fn handle_diff(doc: &mut DocumentMut, path: &str, other: &str) -> Result<Vec<PatchOp>> {
+    let mut other = other
+        .parse::<DocumentMut>()
+        .context("parsing value field in diff request")?;
+    let base = match path {
+        "" => vec![],
+        path => key_path::split(path),
+    };
+
+    // an item that is missing on either side is treated as null
+    let from = traverse(TraverseOps::Get, doc, path)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let to = traverse(TraverseOps::Get, &mut other, path)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    Ok(diff(&from, &to, &base))
+}
+
+/*
+Computes the patch that turns `from` into `to`, with every path prefixed by `path`.
+Objects are compared key by key and arrays index by index, so the patch only
+touches what actually changed. Ops are ordered so that they can be applied one
+after another, e.g. array members are removed from the back.
+*/
+pub fn diff(from: &JValue, to: &JValue, path: &[String]) -> Vec<PatchOp> {
+    let mut patch = vec![];
+    diff_into(from, to, &mut path.to_vec(), &mut patch);
+    patch
+}
+
+fn diff_into(from: &JValue, to: &JValue, path: &mut Vec<String>, patch: &mut Vec<PatchOp>) {
+    match (from, to) {
+        (from, to) if from == to => {}
+        (_, JValue::Null) => patch.push(PatchOp::Remove { path: path.clone() }),
+        // TOML has no null, so it stands for a missing item
+        (JValue::Null, to) => patch.push(PatchOp::Add {
+            path: path.clone(),
+            value: to.clone(),
+        }),
+        (JValue::Object(from), JValue::Object(to)) => {
+            for key in from.keys().filter(|key| !to.contains_key(*key)) {
+                path.push(key.clone());
+                patch.push(PatchOp::Remove { path: path.clone() });
+                path.pop();
+            }
+            for (key, to_value) in to {
+                path.push(key.clone());
+                match from.get(key) {
+                    Some(from_value) => diff_into(from_value, to_value, path, patch),
+                    None if to_value.is_null() => {}
+                    None => patch.push(PatchOp::Add {
+                        path: path.clone(),
+                        value: to_value.clone(),
+                    }),
+                }
+                path.pop();
+            }
+        }
+        (JValue::Array(from), JValue::Array(to)) => {
+            for (index, (from_value, to_value)) in from.iter().zip(to.iter()).enumerate() {
+                path.push(index.to_string());
+                diff_into(from_value, to_value, path, patch);
+                path.pop();
+            }
+            for index in (to.len()..from.len()).rev() {
+                path.push(index.to_string());
+                patch.push(PatchOp::Remove { path: path.clone() });
+                path.pop();
+            }
+            for (index, to_value) in to.iter().enumerate().skip(from.len()) {
+                path.push(index.to_string());
+                patch.push(PatchOp::Add {
+                    path: path.clone(),
+                    value: to_value.clone(),
+                });
+                path.pop();
+            }
+        }
+        (_, to) => patch.push(PatchOp::Replace {
+            path: path.clone(),
+            value: to.clone(),
+        }),
+    }
+}
+
+// the value at a path, or null if there is none
+pub fn lookup(json: &JValue, path: &[String]) -> JValue {
+    let mut current = json;
+    for segment in path {
+        let inner = match current {
+            JValue::Object(map) => map.get(segment),
+            JValue::Array(array) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get(index)),
+            _ => None,
+        };
+        match inner {
+            Some(inner) => current = inner,
+            None => return JValue::Null,
+        }
+    }
+    current.clone()
+}
+
+#[cfg(test)]
+mod differ_tests {
+    use super::*;
+
+    fn path(path: &str) -> Vec<String> {
+        key_path::split(path)
+    }
+
+    #[test]
+    fn diff_equal() {
+        let doc = json!({ "run": "python3 main.py", "ports": [{ "localPort": 8080 }] });
+        assert_eq!(diff(&doc, &doc, &[]), vec![]);
+    }
+
+    #[test]
+    fn diff_objects() {
+        let from = json!({ "run": "python3 main.py", "hidden": [".cache"], "nix": { "channel": "stable-23_05" } });
+        let to = json!({ "run": "python3 app.py", "nix": { "channel": "stable-23_05", "packages": [] } });
+        assert_eq!(
+            diff(&from, &to, &[]),
+            vec![
+                PatchOp::Remove {
+                    path: path("hidden")
+                },
+                PatchOp::Replace {
+                    path: path("run"),
+                    value: json!("python3 app.py")
+                },
+                PatchOp::Add {
+                    path: path("nix/packages"),
+                    value: json!([])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_arrays() {
+        let from = json!([1, 2, 3, 4]);
+        let to = json!([1, 5]);
+        assert_eq!(
+            diff(&from, &to, &path("arr")),
+            vec![
+                PatchOp::Replace {
+                    path: path("arr/1"),
+                    value: json!(5)
+                },
+                PatchOp::Remove {
+                    path: path("arr/3")
+                },
+                PatchOp::Remove {
+                    path: path("arr/2")
+                },
+            ]
+        );
+
+        assert_eq!(
+            diff(&to, &from, &path("arr")),
+            vec![
+                PatchOp::Replace {
+                    path: path("arr/1"),
+                    value: json!(2)
+                },
+                PatchOp::Add {
+                    path: path("arr/2"),
+                    value: json!(3)
+                },
+                PatchOp::Add {
+                    path: path("arr/3"),
+                    value: json!(4)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn patch_op_to_json() {
+        let op = PatchOp::Add {
+            path: path("env/a~1b"),
+            value: json!({ "x": 1 }),
+        };
+        assert_eq!(
+            op.to_json(),
+            json!({ "op": "add", "path": "env/a~1b", "value": "{\"x\":1}" })
+        );
+    }
+
+    #[test]
+    fn diff_documents() {
+        let mut doc = r#"
+run = "python3 main.py"  # comments do not matter
+
+[[ports]]
+localPort = 8080
+"#
+        .parse::<DocumentMut>()
+        .unwrap();
+        let other = r#"
+run = "python3 app.py"
+ports = [{ localPort = 8080 }, { localPort = 3000 }]
+"#;
+
+        let patch = handle_diff(&mut doc, "", other).unwrap();
+        assert_eq!(
+            patch.iter().map(|op| op.to_json()).collect::<Vec<_>>(),
+            vec![
+                json!({ "op": "replace", "path": "run", "value": "\"python3 app.py\"" }),
+                json!({ "op": "add", "path": "ports/1", "value": "{\"localPort\":3000}" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_subtree() {
+        let mut doc = "[nix]\nchannel = \"stable-23_05\""
+            .parse::<DocumentMut>()
+            .unwrap();
+        let patch = handle_diff(&mut doc, "nix", "").unwrap();
+        assert_eq!(patch, vec![PatchOp::Remove { path: path("nix") }]);
+    }
+}
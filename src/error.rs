@@ -0,0 +1,65 @@
+use std::any::Any;
+use std::fmt;
+
+use serde_json::Value as JValue;
+
+/*
+An error that clients can tell apart by its `code`, e.g. `internal_error`,
+rather than by parsing the message. If it was caused by a particular op, the
+op is handed back as it was sent.
+*/
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: &'static str,
+    pub message: String,
+    pub op: Option<JValue>,
+}
+
+impl CodedError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> CodedError {
+        CodedError {
+            code,
+            message: message.into(),
+            op: None,
+        }
+    }
+
+    pub fn with_op(mut self, op: JValue) -> CodedError {
+        self.op = Some(op);
+        self
+    }
+
+    // a panic is reported as an internal error with the message it was raised with
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> CodedError {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        };
+        CodedError::new("internal_error", format!("internal error: {}", message))
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn panic_becomes_internal_error() {
+        let payload = panic::catch_unwind(|| panic!("index {} out of bounds", 3)).unwrap_err();
+        let error = CodedError::from_panic(payload);
+        assert_eq!(error.code, "internal_error");
+        assert_eq!(error.to_string(), "internal error: index 3 out of bounds");
+    }
+}
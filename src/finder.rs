@@ -0,0 +1,198 @@
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+use regex::Regex;
+use serde_json::Value as JValue;
+use toml_edit::{DocumentMut, ImDocument, Item, Table, Value};
+
+use crate::key_path;
+use crate::traversal::{traverse, Search, TraverseOps};
+
+/*
+Finds every entry below `path` whose key matches the `key` glob and/or whose
+value matches the `value` regex. Each match is returned with the line it is on:
+```
+{ "path": "ports/0/localPort", "value": 8080, "line": 12 }
+```
+Like `get`, a path that isn't there returns null, while invalid patterns are errors.
+*/
+pub fn handle_find(
+    doc: &mut DocumentMut,
+    path: &str,
+    key: Option<&str>,
+    value: Option<&str>,
+) -> Result<JValue> {
+    if key.is_none() && value.is_none() {
+        bail!("find needs a key pattern, a value pattern or both");
+    }
+
+    let search = Search {
+        key: key
+            .map(Pattern::new)
+            .transpose()
+            .context("parsing key pattern in find request")?,
+        value: value
+            .map(Regex::new)
+            .transpose()
+            .context("parsing value pattern in find request")?,
+    };
+
+    if let Err(error) = traverse(TraverseOps::Type, doc, path) {
+        eprintln!("Error processing {}: {}", path, error);
+        return Ok(JValue::Null);
+    }
+
+    // the document we edit has no spans, so look up the lines in a freshly parsed copy
+    let source = doc.to_string();
+    let spanned = ImDocument::parse(source.as_str()).context("parsing document for spans")?;
+
+    let mut found = traverse(TraverseOps::Find(search), doc, path)?.unwrap_or_default();
+    if let JValue::Array(matches) = &mut found {
+        for found in matches.iter_mut() {
+            let line = found["path"]
+                .as_str()
+                .and_then(|path| span_of_table(spanned.as_table(), &key_path::split(path)))
+                .map(|span| source[..span.start].matches('\n').count() + 1);
+            found["line"] = line.into();
+        }
+    }
+
+    Ok(found)
+}
+
+// the span of the key of an entry, or of the value itself for array members
+fn span_of(item: &Item, path: &[String]) -> Option<Range<usize>> {
+    match item {
+        Item::Table(table) => span_of_table(table, path),
+        Item::ArrayOfTables(array) => match path.split_first() {
+            None => array.span(),
+            Some((index, [])) => array.get(index.parse::<usize>().ok()?)?.span(),
+            Some((index, rest)) => span_of_table(array.get(index.parse::<usize>().ok()?)?, rest),
+        },
+        Item::Value(value) => span_of_value(value, path),
+        Item::None => None,
+    }
+}
+
+pub fn span_of_table(table: &Table, path: &[String]) -> Option<Range<usize>> {
+    match path.split_first() {
+        None => table.span(),
+        Some((segment, [])) => {
+            let (key, inner) = table.get_key_value(segment)?;
+            key.span().or_else(|| inner.span())
+        }
+        Some((segment, rest)) => span_of(table.get(segment)?, rest),
+    }
+}
+
+fn span_of_value(value: &Value, path: &[String]) -> Option<Range<usize>> {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return value.span(),
+    };
+
+    match value {
+        Value::Array(array) => span_of_value(array.get(segment.parse::<usize>().ok()?)?, rest),
+        Value::InlineTable(table) => {
+            let (key, inner) = table.get_key_value(segment)?;
+            match (rest, inner) {
+                ([], _) => key.span(),
+                (_, Item::Value(inner)) => span_of_value(inner, rest),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod finder_tests {
+    use super::*;
+    use serde_json::json;
+
+    const DOTREPLIT_CONTENT: &str = r#"run = ["python3", "main.py"]
+
+[env]
+REPL_HOME = "/home/runner/${REPL_SLUG}"
+PATH = "${REPL_HOME}/bin"
+
+[[ports]]
+localPort = 8080
+externalPort = 80
+
+[[ports]]
+localPort = 3000
+externalPort = 8080
+"#;
+
+    #[test]
+    fn find_by_value() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let found = handle_find(&mut doc, "", None, Some("^8080$")).unwrap();
+        assert_eq!(
+            found,
+            json!([
+                { "path": "ports/0/localPort", "value": 8080, "line": 8 },
+                { "path": "ports/1/externalPort", "value": 8080, "line": 13 },
+            ])
+        );
+    }
+
+    #[test]
+    fn find_by_key() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let found = handle_find(&mut doc, "", Some("REPL_*"), None).unwrap();
+        assert_eq!(
+            found,
+            json!([{ "path": "env/REPL_HOME", "value": "/home/runner/${REPL_SLUG}", "line": 4 }])
+        );
+    }
+
+    #[test]
+    fn find_by_key_and_value() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let found = handle_find(&mut doc, "", Some("*"), Some("REPL_HOME")).unwrap();
+        assert_eq!(
+            found,
+            json!([{ "path": "env/PATH", "value": "${REPL_HOME}/bin", "line": 5 }])
+        );
+    }
+
+    #[test]
+    fn find_by_path_pattern_in_subtree() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let found = handle_find(&mut doc, "run", Some("run/*"), Some("py")).unwrap();
+        assert_eq!(
+            found,
+            json!([
+                { "path": "run/0", "value": "python3", "line": 1 },
+                { "path": "run/1", "value": "main.py", "line": 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn find_without_patterns() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        assert!(handle_find(&mut doc, "", None, None).is_err());
+    }
+
+    #[test]
+    fn find_rejects_invalid_patterns() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let err = handle_find(&mut doc, "", None, Some("(")).unwrap_err();
+        assert_eq!(err.to_string(), "parsing value pattern in find request");
+        let err = handle_find(&mut doc, "", Some("[a"), None).unwrap_err();
+        assert_eq!(err.to_string(), "parsing key pattern in find request");
+        // even below a path that isn't there
+        assert!(handle_find(&mut doc, "nix", None, Some("(")).is_err());
+    }
+
+    #[test]
+    fn find_below_missing_path() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let found = handle_find(&mut doc, "nix", None, Some("stable")).unwrap();
+        assert_eq!(found, JValue::Null);
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{self, BufRead, Read, Write};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::error::CodedError;
+use crate::limits;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Framing {
+    /// One message per line
+    Lines,
+    /// `Content-Length: <bytes>` and an empty line before every message
+    ContentLength,
+}
+
+/*
+Reads the next message, of at most `limit` bytes. A message that is too long
+or isn't valid UTF-8 is reported as an error and skipped, so that the next one
+is read as usual. Returns None at the end of the input.
+
+Headers without a valid `Content-Length` fail with an `InvalidData` error
+instead: where their message ends is unknown, so nothing after them can be
+read reliably anymore.
+*/
+pub fn read(
+    reader: &mut impl BufRead,
+    framing: Framing,
+    limit: usize,
+) -> io::Result<Option<Result<String>>> {
+    match framing {
+        Framing::Lines => limits::read_line(reader, limit),
+        Framing::ContentLength => read_content_length(reader, limit),
+    }
+}
+
+pub fn write(writer: &mut impl Write, framing: Framing, message: &str) -> io::Result<()> {
+    match framing {
+        Framing::Lines => writeln!(writer, "{}", message)?,
+        Framing::ContentLength => write!(
+            writer,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )?,
+    }
+    writer.flush()
+}
+
+fn read_content_length(
+    reader: &mut impl BufRead,
+    limit: usize,
+) -> io::Result<Option<Result<String>>> {
+    let mut headers = vec![];
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        // headers are short, a longer one is read in pieces that don't parse
+        if reader.by_ref().take(1024).read_line(&mut header)? == 0 {
+            return match headers.is_empty() {
+                true => Ok(None),
+                false => Err(unframed("the input ended in the headers")),
+            };
+        }
+        let header = header.trim_end().to_string();
+        if header.is_empty() {
+            match (length, headers.is_empty()) {
+                (Some(_), _) => break,
+                // blank lines between messages
+                (None, true) => continue,
+                (None, false) => return Err(unframed("there is no Content-Length header")),
+            }
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                match value.trim().parse::<usize>() {
+                    Ok(value) => length = Some(value),
+                    Err(_) => return Err(unframed(&format!("invalid header: {}", header))),
+                }
+            }
+        }
+        headers.push(header);
+    }
+
+    let length = length.unwrap_or_default();
+    if length > limit {
+        io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+        return Ok(Some(Err(CodedError::new(
+            "limit_exceeded",
+            format!("the message has {} bytes, the limit is {}", length, limit),
+        )
+        .into())));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8(body).map_err(|err| err.into())))
+}
+
+fn unframed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod framing_tests {
+    use super::*;
+
+    #[test]
+    fn framing_content_length() {
+        let mut input =
+            "Content-Length: 3\r\n\r\n[1]Content-Length: 20\r\n\r\n[\"much too long\"]   \r\nContent-Type: application/json\r\ncontent-length: 3\r\n\r\n[2]"
+                .as_bytes();
+        let mut read = || read(&mut input, Framing::ContentLength, 16).unwrap();
+        assert_eq!(read().unwrap().unwrap(), "[1]");
+        assert!(read().unwrap().is_err());
+        assert_eq!(read().unwrap().unwrap(), "[2]");
+        assert!(read().is_none());
+
+        // the messages after a bad length can't be found, so reading stops there
+        for bad in [
+            "Content-Length: three\r\n\r\n[1]Content-Length: 3\r\n\r\n[2]",
+            "Content-Type: application/json\r\n\r\n[1]Content-Length: 3\r\n\r\n[2]",
+            "Content-Length: 3\r\n",
+        ] {
+            let err = super::read(&mut bad.as_bytes(), Framing::ContentLength, 16).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut output = vec![];
+        write(&mut output, Framing::ContentLength, "[1]").unwrap();
+        assert_eq!(output, b"Content-Length: 3\r\n\r\n[1]");
+    }
+}
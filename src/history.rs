@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde_json::{json, Value as JValue};
+use toml_edit::DocumentMut;
+
+use crate::differ::{diff, PatchOp};
+use crate::key_path;
+use crate::raw::{get_raw, get_raw_key_value};
+use crate::traversal::to_json;
+
+#[derive(Clone, Copy)]
+pub enum Step {
+    Undo,
+    Redo,
+}
+
+/*
+The undo and redo stacks of a file. Every entry is the inverse of one batch:
+the ops, in the same format as the ops we read, that take the file back to
+what it was before the batch. Only the last `limit` batches are kept.
+*/
+pub struct History {
+    limit: usize,
+    undo: VecDeque<Vec<JValue>>,
+    redo: Vec<Vec<JValue>>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            limit,
+            undo: VecDeque::new(),
+            redo: vec![],
+        }
+    }
+
+    // a new batch makes everything that was undone before unreachable
+    pub fn record(&mut self, inverse: Vec<JValue>) {
+        if inverse.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.push_undo(inverse);
+    }
+
+    // the inverses of up to `count` batches, most recent first
+    pub fn peek(&self, step: Step, count: usize) -> Vec<Vec<JValue>> {
+        match step {
+            Step::Undo => self.undo.iter().rev().take(count).cloned().collect(),
+            Step::Redo => self.redo.iter().rev().take(count).cloned().collect(),
+        }
+    }
+
+    // Once the entries returned by `peek` have been applied, they move over to
+    // the other stack in the form of their own inverses.
+    pub fn stepped(&mut self, step: Step, inverses: Vec<Vec<JValue>>) {
+        for inverse in inverses {
+            match step {
+                Step::Undo => {
+                    self.undo.pop_back();
+                    self.redo.push(inverse);
+                }
+                Step::Redo => {
+                    self.redo.pop();
+                    self.push_undo(inverse);
+                }
+            }
+        }
+    }
+
+    fn push_undo(&mut self, inverse: Vec<JValue>) {
+        self.undo.push_back(inverse);
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+}
+
+/*
+Computes the ops that turn `after` back into `before`. Whatever has to be put
+back is restored from the source text of `before` with `set_raw`, so removed
+items come back with their comments and formatting:
+```
+[{ "op": "set_raw", "path": "nix", "value": "# the nix channel\n[nix]\nchannel = \"stable-23_05\"\n" }]
+```
+A removed key of a table comes back with the comments above it, at the place
+among the other keys where it was:
+```
+[{ "op": "set_raw", "path": "run", "value": "# how to start\nrun = \"x\"\n", "index": 0 }]
+```
+*/
+pub fn inverse_patch(before: &mut DocumentMut, after: &mut DocumentMut) -> Result<Vec<JValue>> {
+    let from = to_json(after)?;
+    let to = to_json(before)?;
+
+    diff(&from, &to, &[])
+        .iter()
+        .map(|op| match op {
+            PatchOp::Remove { .. } => Ok(op.to_json()),
+            PatchOp::Add { path, .. } => {
+                let path = key_path::join(path);
+                match get_raw_key_value(before, &path) {
+                    Some((value, index)) => Ok(json!({
+                        "op": "set_raw",
+                        "path": path,
+                        "value": value,
+                        "index": index,
+                    })),
+                    None => Ok(
+                        json!({ "op": "set_raw", "path": path, "value": get_raw(before, &path)? }),
+                    ),
+                }
+            }
+            PatchOp::Replace { path, .. } => {
+                let path = key_path::join(path);
+                let value = get_raw(before, &path)?;
+                Ok(json!({ "op": "set_raw", "path": path, "value": value }))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = History::new(2);
+        for index in 0..3 {
+            history.record(vec![json!(index)]);
+        }
+        assert_eq!(
+            history.peek(Step::Undo, 5),
+            vec![vec![json!(2)], vec![json!(1)]]
+        );
+    }
+
+    #[test]
+    fn undo_and_redo_move_entries() {
+        let mut history = History::new(10);
+        history.record(vec![json!("first")]);
+        history.record(vec![json!("second")]);
+
+        history.stepped(Step::Undo, vec![vec![json!("redo second")]]);
+        assert_eq!(history.peek(Step::Undo, 5), vec![vec![json!("first")]]);
+        assert_eq!(
+            history.peek(Step::Redo, 5),
+            vec![vec![json!("redo second")]]
+        );
+
+        // a new batch drops what could have been redone
+        history.record(vec![json!("third")]);
+        assert!(history.peek(Step::Redo, 5).is_empty());
+    }
+
+    #[test]
+    fn inverse_restores_removed_table_with_comments() {
+        let source = r#"run = "python3 main.py"  # start here
+
+# the nix channel
+[nix]
+channel = "stable-23_05"
+"#;
+        let mut before = source.parse::<DocumentMut>().unwrap();
+        let mut after = r#"run = "python3 app.py"  # start here
+"#
+        .parse::<DocumentMut>()
+        .unwrap();
+
+        let inverse = inverse_patch(&mut before, &mut after).unwrap();
+        assert_eq!(
+            inverse,
+            vec![
+                json!({ "op": "set_raw", "path": "run", "value": "\"python3 main.py\"  # start here" }),
+                json!({
+                    "op": "set_raw",
+                    "path": "nix",
+                    "value": "\n# the nix channel\n[nix]\nchannel = \"stable-23_05\"\n",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn inverse_restores_removed_key_in_place() {
+        let source = "# how to start\nrun = \"x\"  # entry\nb = 1\n";
+        let mut before = source.parse::<DocumentMut>().unwrap();
+        let mut after = "b = 1\n".parse::<DocumentMut>().unwrap();
+
+        let inverse = inverse_patch(&mut before, &mut after).unwrap();
+        assert_eq!(
+            inverse,
+            vec![json!({
+                "op": "set_raw",
+                "path": "run",
+                "value": "# how to start\nrun = \"x\"  # entry\n",
+                "index": 0,
+            })]
+        );
+    }
+}
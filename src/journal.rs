@@ -0,0 +1,170 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JValue;
+use toml_edit::DocumentMut;
+
+use crate::differ::diff;
+use crate::key_path;
+use crate::traversal::to_json;
+
+/*
+One line of the journal, written for every batch that changed a file:
+```
+{
+  "timestamp": 1718000000000,
+  "file": ".replit",
+  "ops": [{ "op": "add", "path": "nix/channel", "value": "\"stable-24_05\"" }],
+  "before": "fnv1a64:2f0c6e1b7d6c4a91",
+  "after": "fnv1a64:a83e9c0d51f2b764",
+  "changed": ["nix/channel"],
+  "id": 7,
+  "role": "agent"
+}
+```
+`timestamp` is in milliseconds since the epoch, `before` and `after` identify
+the contents of the file and `changed` lists the paths whose values changed.
+`id` and `role` tell who asked for the batch, as they were sent with the
+request, and are left out when it had none.
+*/
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: u128,
+    pub file: String,
+    pub ops: Vec<JValue>,
+    pub before: String,
+    pub after: String,
+    pub changed: Vec<String>,
+    #[serde(flatten)]
+    pub caller: Caller,
+}
+
+// who a batch was applied for
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Caller {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<JValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+impl Entry {
+    pub fn new(
+        file: &Path,
+        caller: &Caller,
+        ops: Vec<JValue>,
+        before: (&str, &mut DocumentMut),
+        after: (&str, &mut DocumentMut),
+    ) -> Result<Entry> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("reading the clock")?
+            .as_millis();
+
+        Ok(Entry {
+            timestamp,
+            file: file.display().to_string(),
+            ops,
+            before: hash(before.0),
+            after: hash(after.0),
+            changed: changed_paths(before.1, after.1)?,
+            caller: caller.clone(),
+        })
+    }
+}
+
+pub fn append(journal: &Path, entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    // the whole line goes out in a single write so that concurrent writers don't interleave
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("error: writing journal: {:?}", journal))
+}
+
+pub fn read(journal: &Path) -> Result<Vec<Entry>> {
+    fs::read_to_string(journal)
+        .with_context(|| format!("error: reading journal: {:?}", journal))?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("parsing line {} of the journal", index + 1))
+        })
+        .collect()
+}
+
+// 64 bit FNV-1a, which is enough to tell versions of a file apart and is the
+// same on every platform and release
+pub fn hash(contents: &str) -> String {
+    let hash = contents.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("fnv1a64:{:016x}", hash)
+}
+
+fn changed_paths(before: &mut DocumentMut, after: &mut DocumentMut) -> Result<Vec<String>> {
+    let from = to_json(before)?;
+    let to = to_json(after)?;
+
+    Ok(diff(&from, &to, &[])
+        .iter()
+        .map(|op| key_path::join(op.path()))
+        .collect())
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hash_is_stable() {
+        assert_eq!(hash(""), "fnv1a64:cbf29ce484222325");
+        assert_eq!(hash("a"), "fnv1a64:af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn entry_lists_changed_paths() {
+        let before = "run = \"python3 main.py\"\n\n[nix]\nchannel = \"stable-23_05\"\n";
+        let after = "run = \"python3 main.py\"\n";
+        let entry = Entry::new(
+            Path::new(".replit"),
+            &Caller {
+                id: Some(json!("req-7")),
+                role: Some("agent".to_string()),
+            },
+            vec![json!({ "op": "remove", "path": "nix" })],
+            (before, &mut before.parse::<DocumentMut>().unwrap()),
+            (after, &mut after.parse::<DocumentMut>().unwrap()),
+        )
+        .unwrap();
+
+        assert_eq!(entry.changed, vec!["nix"]);
+        assert_eq!(entry.before, hash(before));
+        assert_eq!(entry.after, hash(after));
+
+        let line = serde_json::to_value(&entry).unwrap();
+        assert_eq!(line["id"], "req-7");
+        assert_eq!(line["role"], "agent");
+
+        // a batch that nobody identified itself for leaves both out
+        let entry = Entry {
+            caller: Caller::default(),
+            ..entry
+        };
+        let line = serde_json::to_value(&entry).unwrap();
+        assert!(line.get("id").is_none() && line.get("role").is_none());
+        let read: Entry = serde_json::from_value(line).unwrap();
+        assert!(read.caller.id.is_none());
+    }
+}
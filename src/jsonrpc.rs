@@ -0,0 +1,434 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, json, Map, Value};
+
+use crate::error::CodedError;
+use crate::{handle_request, OpKind, RequestOptions, Session};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+// an op failed, with the code of its `CodedError`, if any, in `data`
+const EDIT_ERROR: i64 = -32000;
+
+// The methods after `toml/`: every op under its tag in camelCase, which it
+// serializes back to, e.g. `toml/getRaw` for `get_raw`, and `applyPatch`.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+enum Method {
+    Add,
+    Get,
+    Describe,
+    Keys,
+    Len,
+    Type,
+    Flatten,
+    Find,
+    Remove,
+    GetRaw,
+    SetRaw,
+    Sync,
+    Replace,
+    Diff,
+    Merge,
+    Undo,
+    Redo,
+    Backups,
+    Restore,
+    ApplyPatch,
+}
+
+/*
+JSON-RPC 2.0, for clients that would rather not speak the ad-hoc protocol.
+Every op is a method whose params are the fields of the op, and whose result
+is what the op returns:
+```
+{ "jsonrpc": "2.0", "id": 1, "method": "toml/get", "params": { "path": "run" } }
+```
+`toml/applyPatch` applies a whole batch in `ops` and returns its `results`,
+`inverse` and whether it `changed` the document, plus the edited `document`
+if it was asked for. Every method takes the options of an envelope in
+`options`, e.g. the `path` of the file to edit. Batches and notifications
+work as the specification says.
+*/
+pub fn handle(
+    message: Result<String>,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Option<String> {
+    let message = match message {
+        Ok(message) => message,
+        Err(err) => {
+            let code = match err.downcast_ref::<CodedError>() {
+                Some(_) => INVALID_REQUEST,
+                None => PARSE_ERROR,
+            };
+            return Some(response(Value::Null, Err(rpc_error(code, err))).to_string());
+        }
+    };
+
+    let call = |request| handle_call(request, dotreplit_filepath, return_output, dry_run, session);
+    let response = match from_str::<Value>(&message) {
+        Err(err) => Some(response(
+            Value::Null,
+            Err(rpc_error(PARSE_ERROR, err.into())),
+        )),
+        Ok(Value::Array(batch)) if batch.is_empty() => Some(response(
+            Value::Null,
+            Err(rpc_error(INVALID_REQUEST, anyhow!("the batch is empty"))),
+        )),
+        // a batch of notifications is answered with nothing at all
+        Ok(Value::Array(batch)) => {
+            let responses = batch.into_iter().filter_map(call).collect::<Vec<_>>();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Ok(request) => call(request),
+    };
+    response.map(|response| response.to_string())
+}
+
+pub struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+fn rpc_error(code: i64, err: anyhow::Error) -> RpcError {
+    let data = err
+        .downcast_ref::<CodedError>()
+        .map(|coded| match &coded.op {
+            Some(op) => json!({ "code": coded.code, "op": op }),
+            None => json!({ "code": coded.code }),
+        });
+    RpcError {
+        code,
+        message: err.to_string(),
+        data,
+    }
+}
+
+pub fn invalid(code: i64, message: &str) -> RpcError {
+    RpcError {
+        code,
+        message: message.to_string(),
+        data: None,
+    }
+}
+
+pub fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => {
+            let mut error = json!({ "code": err.code, "message": err.message });
+            if let Some(data) = err.data {
+                error["data"] = data;
+            }
+            json!({ "jsonrpc": "2.0", "id": id, "error": error })
+        }
+    }
+}
+
+// Answers a single request, or nothing if it is a notification. A request
+// that isn't valid is always answered, since it can't be told apart from one.
+fn handle_call(
+    request: Value,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Option<Value> {
+    let mut request = match request {
+        Value::Object(request) => request,
+        _ => {
+            let err = invalid(INVALID_REQUEST, "a request has to be an object");
+            return Some(response(Value::Null, Err(err)));
+        }
+    };
+    let id = request.remove("id");
+    let reply_to = id.clone().unwrap_or_default();
+    if !matches!(
+        id,
+        None | Some(Value::Null | Value::Number(_) | Value::String(_))
+    ) {
+        let err = invalid(INVALID_REQUEST, "id has to be a number, a string or null");
+        return Some(response(Value::Null, Err(err)));
+    }
+    if request.get("jsonrpc") != Some(&json!("2.0")) {
+        let err = invalid(INVALID_REQUEST, "jsonrpc has to be \"2.0\"");
+        return Some(response(reply_to, Err(err)));
+    }
+    let method = match request.remove("method") {
+        Some(Value::String(method)) => method,
+        _ => {
+            let err = invalid(INVALID_REQUEST, "method has to be a string");
+            return Some(response(reply_to, Err(err)));
+        }
+    };
+
+    let result = call(
+        &method,
+        request.remove("params"),
+        id.as_ref(),
+        dotreplit_filepath,
+        return_output,
+        dry_run,
+        session,
+    );
+    id.map(|id| response(id, result))
+}
+
+fn call(
+    method: &str,
+    params: Option<Value>,
+    id: Option<&Value>,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<Value, RpcError> {
+    let name = method
+        .strip_prefix("toml/")
+        .ok_or_else(|| invalid(METHOD_NOT_FOUND, "methods start with toml/"))?;
+    let name = Method::deserialize(json!(name)).map_err(|_| {
+        let message = format!("there is no method {}", method);
+        invalid(METHOD_NOT_FOUND, &message)
+    })?;
+    let mut params = match params {
+        None => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(_) => return Err(invalid(INVALID_PARAMS, "params have to be an object")),
+    };
+    let options: RequestOptions = match params.remove("options") {
+        Some(options) => {
+            from_value(options).map_err(|err| rpc_error(INVALID_PARAMS, err.into()))?
+        }
+        None => RequestOptions::default(),
+    };
+
+    let ops = match name {
+        Method::ApplyPatch => match params.remove("ops") {
+            Some(Value::Array(ops)) => {
+                // ops that don't parse are the caller's fault, not an error of the edit
+                from_value::<Vec<OpKind>>(Value::Array(ops.clone()))
+                    .map_err(|err| rpc_error(INVALID_PARAMS, err.into()))?;
+                ops
+            }
+            _ => return Err(invalid(INVALID_PARAMS, "ops has to be an array of ops")),
+        },
+        op => {
+            params.insert("op".to_string(), json!(op));
+            let params = Value::Object(params);
+            OpKind::deserialize(&params).map_err(|err| rpc_error(INVALID_PARAMS, err.into()))?;
+            vec![params]
+        }
+    };
+
+    let edited = handle_request(
+        dotreplit_filepath,
+        ops,
+        &options,
+        id,
+        return_output,
+        dry_run,
+        session,
+    )
+    .map_err(|err| {
+        let code = match err.downcast_ref::<CodedError>() {
+            Some(coded) if coded.code == "internal_error" => INTERNAL_ERROR,
+            _ => EDIT_ERROR,
+        };
+        rpc_error(code, err)
+    })?;
+
+    if name != Method::ApplyPatch {
+        return Ok(edited.outputs.into_iter().next().unwrap_or_default());
+    }
+    let mut result = json!({
+        "results": edited.outputs,
+        "inverse": edited.inverse,
+        "changed": edited.changed,
+    });
+    if options.return_output(return_output) {
+        result["document"] = json!(edited.contents);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod jsonrpc_tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testing::scratch_file;
+    use crate::Protocol;
+
+    #[test]
+    fn jsonrpc_methods() {
+        let file = scratch_file("jsonrpc", "run = \"python3 main.py\"\n");
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session {
+            protocol: Protocol::Jsonrpc,
+            ..Session::for_test(&dir)
+        };
+        let send = |request: Value| {
+            let response = handle(Ok(request.to_string()), &file, false, false, &session);
+            response.map(|response| from_str::<Value>(&response).unwrap())
+        };
+
+        assert_eq!(
+            send(
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "toml/get", "params": { "path": "run" } })
+            ),
+            Some(json!({ "jsonrpc": "2.0", "id": 1, "result": "python3 main.py" }))
+        );
+        assert_eq!(
+            send(
+                json!({ "jsonrpc": "2.0", "id": "a", "method": "toml/getRaw", "params": { "path": "run" } })
+            ),
+            Some(json!({ "jsonrpc": "2.0", "id": "a", "result": "\"python3 main.py\"" }))
+        );
+        let patch = send(json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "toml/applyPatch",
+            "params": { "ops": [{ "op": "add", "path": "entrypoint", "value": "\"main.py\"" }] },
+        }))
+        .unwrap();
+        assert_eq!(patch["result"]["changed"], json!(true));
+        assert_eq!(
+            patch["result"]["inverse"],
+            json!([{ "op": "remove", "path": "entrypoint" }])
+        );
+        // notifications are applied, but not answered
+        assert_eq!(
+            send(
+                json!({ "jsonrpc": "2.0", "method": "toml/remove", "params": { "path": "entrypoint" } })
+            ),
+            None
+        );
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "run = \"python3 main.py\"\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn jsonrpc_errors() {
+        let file = scratch_file("jsonrpc-errors", "");
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session {
+            protocol: Protocol::Jsonrpc,
+            ..Session::for_test(&dir)
+        };
+        let send = |message: &str| {
+            let response = handle(Ok(message.to_string()), &file, false, false, &session).unwrap();
+            from_str::<Value>(&response).unwrap()
+        };
+        let code = |message: &str| send(message)["error"]["code"].clone();
+
+        assert_eq!(code("{"), json!(PARSE_ERROR));
+        assert_eq!(code("[]"), json!(INVALID_REQUEST));
+        assert_eq!(
+            code(r#"{ "id": 1, "method": "toml/get" }"#),
+            json!(INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/frobnicate" }"#),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/get", "params": {} }"#),
+            json!(INVALID_PARAMS)
+        );
+        // methods are told apart from wrong params by whether the op exists
+        assert_eq!(
+            code(
+                r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/setRaw", "params": { "path": "run" } }"#
+            ),
+            json!(INVALID_PARAMS)
+        );
+        assert_eq!(
+            code(
+                r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/getRaw", "params": { "path": 1 } }"#
+            ),
+            json!(INVALID_PARAMS)
+        );
+        // methods are named in camelCase only
+        assert_eq!(
+            code(
+                r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/get_raw", "params": { "path": "run" } }"#
+            ),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(
+                r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/setRaws", "params": { "path": "run" } }"#
+            ),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            send(r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/backups" }"#)["result"],
+            json!([])
+        );
+        assert_eq!(
+            code(
+                r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/applyPatch", "params": { "ops": [{ "op": "frobnicate" }] } }"#
+            ),
+            json!(INVALID_PARAMS)
+        );
+        let forbidden = send(
+            r#"{ "jsonrpc": "2.0", "id": 1, "method": "toml/get", "params": { "path": "run", "options": { "path": "../.replit" } } }"#,
+        );
+        assert_eq!(forbidden["error"]["code"], json!(EDIT_ERROR));
+        assert_eq!(forbidden["error"]["data"]["code"], json!("forbidden_path"));
+
+        let batch = send(
+            r#"[{ "jsonrpc": "2.0", "id": 1, "method": "toml/keys" }, { "jsonrpc": "2.0", "method": "toml/keys" }, 1]"#,
+        );
+        assert_eq!(batch.as_array().unwrap().len(), 2);
+        assert_eq!(batch[0]["result"], json!([]));
+        assert_eq!(batch[1]["error"]["code"], json!(INVALID_REQUEST));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // the names that serde lists as expected after an unknown one
+    fn expected_names(err: serde_json::Error) -> Vec<String> {
+        let message = err.to_string();
+        let (_, expected) = message.split_once("expected one of").unwrap();
+        expected
+            .split(", ")
+            .map(|name| name.trim().trim_matches('`').to_string())
+            .collect()
+    }
+
+    #[test]
+    fn jsonrpc_methods_match_ops() {
+        let mut ops = expected_names(OpKind::deserialize(json!({ "op": "?" })).err().unwrap());
+        let methods = expected_names(Method::deserialize(json!("?")).err().unwrap());
+
+        let mut tags = methods
+            .iter()
+            .filter(|method| *method != "applyPatch")
+            .map(|method| {
+                let method = Method::deserialize(json!(method)).ok().unwrap();
+                serde_json::to_value(method)
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        tags.sort();
+        ops.sort();
+        assert_eq!(tags, ops);
+    }
+}
@@ -0,0 +1,48 @@
+/*
+Paths are keys joined with "/", e.g. "nix/channel" or "ports/0/localPort".
+Keys that contain "/" or "~" are escaped the same way as in a JSON pointer
+(https://datatracker.ietf.org/doc/html/rfc6901): "~" is written as "~0"
+and "/" as "~1", so the key "a/b" is addressed as "a~1b".
+*/
+
+pub fn split(path: &str) -> Vec<String> {
+    path.split('/').map(unescape).collect()
+}
+
+pub fn join<S: AsRef<str>>(segments: &[S]) -> String {
+    segments
+        .iter()
+        .map(|segment| escape(segment.as_ref()))
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+pub fn escape(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod key_path_tests {
+    use super::*;
+
+    #[test]
+    fn split_plain_path() {
+        assert_eq!(split("ports/0/localPort"), vec!["ports", "0", "localPort"]);
+    }
+
+    #[test]
+    fn split_escaped_path() {
+        assert_eq!(split("env/a~1b/~01"), vec!["env", "a/b", "~1"]);
+    }
+
+    #[test]
+    fn join_round_trips() {
+        let segments = ["env", "a/b", "~1", "~"];
+        assert_eq!(join(&segments), "env/a~1b/~01/~0");
+        assert_eq!(split(&join(&segments)), segments);
+    }
+}
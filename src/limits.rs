@@ -0,0 +1,252 @@
+use std::io::{self, BufRead, Read};
+
+use anyhow::Result;
+use serde_json::Value as JValue;
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::error::CodedError;
+use crate::key_path;
+
+/*
+Bounds on what a client can make the editor do. Going over any limit fails the
+request with `limit_exceeded`.
+
+Paths are walked one segment at a time (`At::down_field`, `descend_*`) and
+documents are serialized recursively, so both are bounded in depth to keep the
+stack safe. The walks don't check the depth themselves, since they don't know
+the limits, and don't need to: every path they are given is bounded before.
+Paths of requests are checked by `check_batch`. Paths that are built by walking
+a document or a value, as for `sync`, `diff`, `merge` and undo, are no deeper
+than that document: the file is checked by `check_document` when it is read,
+and JSON and TOML in requests can't nest deeper than their parsers allow (128
+and 100 levels).
+*/
+pub struct Limits {
+    pub line_bytes: usize,
+    pub document_bytes: usize,
+    pub depth: usize,
+    pub array_len: usize,
+    pub batch_ops: usize,
+}
+
+impl Limits {
+    pub fn check_batch(&self, ops: &[JValue]) -> Result<()> {
+        if ops.len() > self.batch_ops {
+            return Err(exceeded(format!(
+                "the batch has {} ops, the limit is {}",
+                ops.len(),
+                self.batch_ops
+            )));
+        }
+
+        for op in ops {
+            for field in ["path", "table_header_path", "dotted_path"] {
+                if let Some(path) = op.get(field).and_then(|path| path.as_str()) {
+                    let depth = key_path::split(path).len();
+                    if depth > self.depth {
+                        return Err(exceeded(format!(
+                            "{} is {} levels deep, the limit is {}",
+                            field, depth, self.depth
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_size(&self, bytes: u64) -> Result<()> {
+        if bytes > self.document_bytes as u64 {
+            return Err(exceeded(format!(
+                "the document has {} bytes, the limit is {}",
+                bytes, self.document_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    // walks the document without recursing, since it may be too deep to recurse over
+    pub fn check_document(&self, doc: &DocumentMut) -> Result<()> {
+        enum Node<'a> {
+            Item(&'a Item),
+            Value(&'a Value),
+        }
+
+        let mut stack = vec![(Node::Item(doc.as_item()), 0)];
+        while let Some((node, depth)) = stack.pop() {
+            if depth > self.depth {
+                return Err(exceeded(format!(
+                    "the document nests more than {} levels deep",
+                    self.depth
+                )));
+            }
+
+            let len = match node {
+                Node::Item(Item::Table(table)) => {
+                    stack.extend(table.iter().map(|(_, item)| (Node::Item(item), depth + 1)));
+                    None
+                }
+                Node::Item(Item::ArrayOfTables(array)) => {
+                    stack.extend(
+                        array
+                            .iter()
+                            .flat_map(|table| table.iter())
+                            .map(|(_, item)| (Node::Item(item), depth + 2)),
+                    );
+                    Some(array.len())
+                }
+                Node::Item(Item::Value(value)) | Node::Value(value) => match value {
+                    Value::Array(array) => {
+                        stack.extend(array.iter().map(|value| (Node::Value(value), depth + 1)));
+                        Some(array.len())
+                    }
+                    Value::InlineTable(table) => {
+                        stack.extend(
+                            table
+                                .iter()
+                                .map(|(_, value)| (Node::Value(value), depth + 1)),
+                        );
+                        None
+                    }
+                    _ => None,
+                },
+                Node::Item(Item::None) => None,
+            };
+
+            if let Some(len) = len.filter(|len| *len > self.array_len) {
+                return Err(exceeded(format!(
+                    "an array has {} members, the limit is {}",
+                    len, self.array_len
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+Reads a line of at most `limit` bytes. A longer line is skipped up to its end
+and reported as an error, so that the next line is read as usual. Returns None
+at the end of the input.
+*/
+pub fn read_line(reader: &mut impl BufRead, limit: usize) -> io::Result<Option<Result<String>>> {
+    let mut line = vec![];
+    let read = reader
+        .by_ref()
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > limit {
+        let mut skipped = line.len();
+        loop {
+            let (done, used) = {
+                let available = reader.fill_buf()?;
+                match available.iter().position(|byte| *byte == b'\n') {
+                    Some(end) => (true, end + 1),
+                    None => (available.is_empty(), available.len()),
+                }
+            };
+            reader.consume(used);
+            skipped += used;
+            if done {
+                break;
+            }
+        }
+        return Ok(Some(Err(exceeded(format!(
+            "the line has at least {} bytes, the limit is {}",
+            skipped, limit
+        )))));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(String::from_utf8(line).map_err(|err| err.into())))
+}
+
+/*
+Reads all of the input, which may have at most `limit` bytes. A longer input
+is an error as soon as the byte past the limit is read, without reading the
+rest of it.
+*/
+pub fn read_all(reader: impl Read, limit: usize) -> Result<String> {
+    let mut input = String::new();
+    reader.take(limit as u64 + 1).read_to_string(&mut input)?;
+    if input.len() > limit {
+        return Err(exceeded(format!(
+            "the input has more than {} bytes, the limit is {}",
+            limit, limit
+        )));
+    }
+    Ok(input)
+}
+
+fn exceeded(message: String) -> anyhow::Error {
+    CodedError::new("limit_exceeded", message).into()
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use super::*;
+    use serde_json::json;
+
+    const LIMITS: Limits = Limits {
+        line_bytes: 16,
+        document_bytes: 64,
+        depth: 3,
+        array_len: 2,
+        batch_ops: 2,
+    };
+
+    fn code(result: Result<()>) -> &'static str {
+        result.unwrap_err().downcast::<CodedError>().unwrap().code
+    }
+
+    #[test]
+    fn limits_batch() {
+        assert!(LIMITS
+            .check_batch(&[json!({ "op": "get", "path": "a/b/c" })])
+            .is_ok());
+        assert_eq!(
+            code(LIMITS.check_batch(&[json!({ "op": "get", "path": "a/b/c/d" })])),
+            "limit_exceeded"
+        );
+        assert_eq!(
+            code(LIMITS.check_batch(&[json!({}), json!({}), json!({})])),
+            "limit_exceeded"
+        );
+        assert_eq!(code(LIMITS.check_size(65)), "limit_exceeded");
+    }
+
+    #[test]
+    fn limits_document() {
+        let check = |source: &str| LIMITS.check_document(&source.parse::<DocumentMut>().unwrap());
+        assert!(check("a = [1, 2]\n[b]\nd = { e = 1 }").is_ok());
+        assert_eq!(code(check("a = [1, 2, 3]")), "limit_exceeded");
+        assert_eq!(code(check("a.b.c.d = 1")), "limit_exceeded");
+        assert_eq!(code(check("a = [[[[1]]]]")), "limit_exceeded");
+    }
+
+    #[test]
+    fn limits_lines() {
+        let mut input = "[1]\nthis line is much too long\n[2]".as_bytes();
+        assert_eq!(read_line(&mut input, 16).unwrap().unwrap().unwrap(), "[1]");
+        assert!(read_line(&mut input, 16).unwrap().unwrap().is_err());
+        assert_eq!(read_line(&mut input, 16).unwrap().unwrap().unwrap(), "[2]");
+        assert!(read_line(&mut input, 16).unwrap().is_none());
+    }
+
+    #[test]
+    fn limits_input() {
+        assert_eq!(read_all("[1]\n[2]".as_bytes(), 7).unwrap(), "[1]\n[2]");
+        assert_eq!(
+            code(read_all("[1]\n[2]\n".as_bytes(), 7).map(drop)),
+            "limit_exceeded"
+        );
+    }
+}
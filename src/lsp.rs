@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+
+use anyhow::Result;
+use serde_json::{from_str, json, Value};
+use toml_edit::{DocumentMut, ImDocument, Item, Table};
+
+use crate::differ::lookup;
+use crate::finder::span_of_table;
+use crate::framing::{self, Framing};
+use crate::jsonrpc::{invalid, response, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND};
+use crate::schema::{self, Field, Kind};
+use crate::traversal::parse_json;
+use crate::{edit_content, key_path, Session};
+
+const SOURCE: &str = "toml-editor";
+// severities of diagnostics
+const ERROR: u8 = 1;
+const WARNING: u8 = 2;
+// kinds of completion items
+const PROPERTY: u8 = 10;
+const VALUE: u8 = 12;
+const STRUCT: u8 = 22;
+
+/*
+A language server for `.replit` files, speaking LSP over stdio. Documents are
+synced in full and checked on every change, for parse errors and against the
+`schema`, which also provides hover docs and completions. Formatting only
+evens out whitespace, since everything else is kept as it was written, and
+the quick fixes for schema violations are batches of `add` and `remove` ops,
+applied like content sent with a request.
+
+Returns the exit code, which is 0 only if the client asked to shut down first.
+*/
+pub fn serve(reader: &mut impl BufRead, writer: &mut impl Write, session: &Session) -> Result<i32> {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut shutdown = false;
+
+    while let Some(message) =
+        framing::read(reader, Framing::ContentLength, session.limits.line_bytes)?
+    {
+        let message: Value = match message.and_then(|message| Ok(from_str(&message)?)) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("error: {:#}", err);
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        let id = match message.get("id") {
+            // the server sends no requests, so there are no responses to read
+            Some(_) if method.is_empty() => continue,
+            Some(id) => id.clone(),
+            None => {
+                let text = match method {
+                    "exit" => break,
+                    "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+                    // every change carries the whole document
+                    "textDocument/didChange" => params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change["text"].as_str()),
+                    "textDocument/didClose" => {
+                        documents.remove(uri);
+                        publish(writer, uri, vec![])?;
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(text) = text {
+                    documents.insert(uri.to_string(), text.to_string());
+                    publish(writer, uri, diagnostics(text))?;
+                }
+                continue;
+            }
+        };
+
+        let document = || {
+            documents
+                .get(uri)
+                .ok_or_else(|| invalid(INVALID_PARAMS, "the document is not open"))
+        };
+        let result: Result<Value, RpcError> = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => document().map(|text| hover(text, &params["position"])),
+            "textDocument/completion" => {
+                document().map(|text| completion(text, &params["position"]))
+            }
+            "textDocument/formatting" => document().map(|text| formatting(text)),
+            "textDocument/codeAction" => document()
+                .map(|text| code_actions(text, uri, &params["context"]["diagnostics"], session)),
+            method => Err(invalid(
+                METHOD_NOT_FOUND,
+                &format!("there is no method {}", method),
+            )),
+        };
+        framing::write(
+            writer,
+            Framing::ContentLength,
+            &response(id, result).to_string(),
+        )?;
+    }
+
+    Ok(if shutdown { 0 } else { 1 })
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            // the whole document is sent on every change
+            "textDocumentSync": 1,
+            "hoverProvider": true,
+            "completionProvider": { "triggerCharacters": ["[", "="] },
+            "documentFormattingProvider": true,
+            "codeActionProvider": true,
+        },
+        "serverInfo": { "name": SOURCE, "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+fn publish(writer: &mut impl Write, uri: &str, diagnostics: Vec<Value>) -> Result<()> {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    });
+    framing::write(writer, Framing::ContentLength, &notification.to_string())?;
+    Ok(())
+}
+
+fn diagnostics(text: &str) -> Vec<Value> {
+    let spanned = match ImDocument::parse(text) {
+        Ok(spanned) => spanned,
+        Err(err) => {
+            return vec![json!({
+                "range": range(text, err.span().unwrap_or_default()),
+                "severity": ERROR,
+                "source": SOURCE,
+                "message": err.message().trim_end(),
+            })]
+        }
+    };
+    let json = match parse_json(text) {
+        Some(json) => json,
+        None => return vec![],
+    };
+
+    schema::check(&json)
+        .into_iter()
+        .map(|violation| {
+            let span = span_of_table(spanned.as_table(), &violation.path).unwrap_or_default();
+            json!({
+                "range": range(text, span),
+                "severity": if violation.unknown { WARNING } else { ERROR },
+                "source": SOURCE,
+                "message": violation.message,
+                // what the code actions work with
+                "data": { "path": key_path::join(&violation.path), "unknown": violation.unknown },
+            })
+        })
+        .collect()
+}
+
+fn hover(text: &str, position: &Value) -> Value {
+    let spanned = match ImDocument::parse(text) {
+        Ok(spanned) => spanned,
+        Err(_) => return Value::Null,
+    };
+    let mut path = vec![];
+    let span = match path_at(spanned.as_table(), offset(text, position), &mut path) {
+        Some(span) => span,
+        None => return Value::Null,
+    };
+    let field = match schema::field_at(&path) {
+        Some(field) => field,
+        None => return Value::Null,
+    };
+
+    let mut contents = format!(
+        "**{}**: {}\n\n{}",
+        field.key,
+        field.kind.describe(),
+        field.doc
+    );
+    if !field.values.is_empty() {
+        let values = field
+            .values
+            .iter()
+            .map(|value| format!("`{}`", value))
+            .collect::<Vec<_>>();
+        contents.push_str(&format!("\n\nUsually one of {}", values.join(", ")));
+    }
+    json!({
+        "contents": { "kind": "markdown", "value": contents },
+        "range": range(text, span),
+    })
+}
+
+// Finds the entry whose key or value is at the offset, fills in its path and
+// returns the span of its key.
+fn path_at(table: &Table, offset: usize, path: &mut Vec<String>) -> Option<Range<usize>> {
+    let contains =
+        |span: Option<Range<usize>>| span.filter(|span| span.start <= offset && offset <= span.end);
+
+    for (name, _) in table.iter() {
+        let (key, item) = table.get_key_value(name)?;
+        path.push(name.to_string());
+        let found = match item {
+            _ if contains(key.span()).is_some() => key.span(),
+            Item::Table(inner) => path_at(inner, offset, path),
+            Item::ArrayOfTables(array) => {
+                let mut found = None;
+                for (index, inner) in array.iter().enumerate() {
+                    path.push(index.to_string());
+                    found = path_at(inner, offset, path).or_else(|| contains(inner.span()));
+                    if found.is_some() {
+                        break;
+                    }
+                    path.pop();
+                }
+                found
+            }
+            Item::Value(value) => contains(value.span()).and(key.span()),
+            Item::None => None,
+        };
+        if found.is_some() {
+            return found;
+        }
+        path.pop();
+    }
+    None
+}
+
+/*
+Completes the names of tables in headers, the keys of the table the cursor is
+in and the values that are usually given for a key. This works on the text of
+the line, since a document that is being typed into often doesn't parse.
+*/
+fn completion(text: &str, position: &Value) -> Value {
+    let offset = offset(text, position);
+    let line_start = text[..offset].rfind('\n').map_or(0, |index| index + 1);
+    let line = &text[line_start..offset];
+    let table = header_at(&text[..line_start]);
+
+    let items = if line.trim_start().starts_with('[') {
+        schema::ROOT
+            .iter()
+            .filter(|field| {
+                matches!(
+                    field.kind,
+                    Kind::Table { .. } | Kind::ArrayOfTables(_) | Kind::Map
+                )
+            })
+            .map(|field| item(field, field.key, STRUCT))
+            .collect()
+    } else if let Some((key, _)) = line.split_once('=') {
+        let mut path = table;
+        path.extend(dotted(key));
+        let field = match schema::field_at(&path) {
+            Some(field) => field,
+            None => return json!([]),
+        };
+        let values = match field.kind {
+            Kind::Bool => vec!["true".to_string(), "false".to_string()],
+            _ => field
+                .values
+                .iter()
+                .map(|value| Value::from(*value).to_string())
+                .collect(),
+        };
+        values
+            .iter()
+            .map(|value| item(field, value, VALUE))
+            .collect()
+    } else {
+        // keys that are already there aren't offered again
+        let json = parse_json(text).unwrap_or_default();
+        let present = lookup(&json, &table);
+        let present = present.as_object();
+        schema::fields_at(&table)
+            .unwrap_or_default()
+            .iter()
+            .filter(|field| !present.is_some_and(|present| present.contains_key(field.key)))
+            .map(|field| {
+                let mut item = item(field, field.key, PROPERTY);
+                item["insertText"] = json!(format!("{} = ", field.key));
+                item
+            })
+            .collect::<Vec<_>>()
+    };
+    Value::Array(items)
+}
+
+fn item(field: &Field, label: &str, kind: u8) -> Value {
+    json!({
+        "label": label,
+        "kind": kind,
+        "detail": field.kind.describe(),
+        "documentation": field.doc,
+    })
+}
+
+// the path of the table whose header comes last in the text
+fn header_at(text: &str) -> Vec<String> {
+    text.lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with('[') && !line.contains('='))
+        .map(|header| {
+            let header = header.trim_start_matches('[');
+            dotted(header.split(']').next().unwrap_or_default())
+        })
+        .unwrap_or_default()
+}
+
+fn dotted(key: &str) -> Vec<String> {
+    key.split('.')
+        .map(|segment| {
+            segment
+                .trim()
+                .trim_matches('"')
+                .trim_matches('\'')
+                .to_string()
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+fn formatting(text: &str) -> Value {
+    match format(text) {
+        Some(formatted) if formatted != text => json!([{
+            "range": range(text, 0..text.len()),
+            "newText": formatted,
+        }]),
+        _ => json!([]),
+    }
+}
+
+// Puts a single space on both sides of the `=` of every entry, removes
+// whitespace at the ends of lines and runs of blank lines, and ends the
+// document with a newline. Comments, quoting and the order of keys are left
+// as they were written. Returns None if the document doesn't parse.
+fn format(text: &str) -> Option<String> {
+    let mut doc = text.parse::<DocumentMut>().ok()?;
+    space_entries(doc.as_table_mut());
+    let spaced = doc.to_string();
+
+    let mut lines: Vec<&str> = vec![];
+    for line in spaced.lines().map(str::trim_end) {
+        if line.is_empty() && matches!(lines.last(), None | Some(&"")) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    let formatted = match lines.is_empty() {
+        true => String::new(),
+        false => lines.join("\n") + "\n",
+    };
+
+    // the ends of lines may belong to multi-line strings, which mustn't change
+    match parse_json(&formatted) == parse_json(text) {
+        true => Some(formatted),
+        false => Some(spaced),
+    }
+}
+
+fn space_entries(table: &mut Table) {
+    for (mut key, item) in table.iter_mut() {
+        match item {
+            Item::Value(value) => {
+                key.leaf_decor_mut().set_suffix(" ");
+                value.decor_mut().set_prefix(" ");
+            }
+            Item::Table(inner) => space_entries(inner),
+            Item::ArrayOfTables(array) => array.iter_mut().for_each(space_entries),
+            Item::None => {}
+        }
+    }
+}
+
+// Offers to remove what a diagnostic is about, and to rename unknown keys
+// that look like a typo of a known one.
+fn code_actions(text: &str, uri: &str, diagnostics: &Value, session: &Session) -> Value {
+    let json = parse_json(text).unwrap_or_default();
+    let mut actions = vec![];
+
+    for diagnostic in diagnostics.as_array().into_iter().flatten() {
+        let path = match diagnostic["data"]["path"].as_str() {
+            Some(path) => path,
+            None => continue,
+        };
+        let split = key_path::split(path);
+        let remove = json!({ "op": "remove", "path": path });
+
+        let mut fixes = vec![];
+        if let Some(known) =
+            schema::suggest(&split).filter(|_| diagnostic["data"]["unknown"] == true)
+        {
+            let mut renamed = split.clone();
+            renamed.pop();
+            renamed.push(known.to_string());
+            let renamed = key_path::join(&renamed);
+            let add = json!({
+                "op": "add",
+                "path": renamed,
+                "value": lookup(&json, &split).to_string(),
+            });
+            fixes.push((
+                format!("Rename {} to {}", path, renamed),
+                vec![add, remove.clone()],
+            ));
+        }
+        fixes.push((format!("Remove {}", path), vec![remove]));
+
+        for (title, ops) in fixes {
+            match edit_content(text, ops, None, session) {
+                Ok(edited) => actions.push(json!({
+                    "title": title,
+                    "kind": "quickfix",
+                    "diagnostics": [diagnostic],
+                    "edit": { "changes": { uri: [{
+                        "range": range(text, 0..text.len()),
+                        "newText": edited.contents,
+                    }] } },
+                })),
+                Err(err) => eprintln!("error: {}: {:#}", title, err),
+            }
+        }
+    }
+    Value::Array(actions)
+}
+
+// LSP counts the characters of a line in UTF-16 code units
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, span: Range<usize>) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or_default() as usize;
+    let character = position["character"].as_u64().unwrap_or_default() as usize;
+    let line_start = match line {
+        0 => 0,
+        line => match text.match_indices('\n').nth(line - 1) {
+            Some((index, _)) => index + 1,
+            None => return text.len(),
+        },
+    };
+
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod lsp_tests {
+    use super::*;
+
+    const REPLIT: &str =
+        "run = \"python3 main.py\"\nentrypoint = 1\n\n[nix]\nchanel = \"stable-24_05\"\n";
+
+    fn at(line: u64, character: u64) -> Value {
+        json!({ "line": line, "character": character })
+    }
+
+    #[test]
+    fn lsp_positions() {
+        let text = "a = \"é😀\"\nb = 1\n";
+        // the emoji takes two code units
+        assert_eq!(offset(text, &at(0, 8)), "a = \"é😀".len());
+        assert_eq!(position(text, "a = \"é😀".len()), at(0, 8));
+        assert_eq!(offset(text, &at(1, 2)), text.find("= 1").unwrap());
+        assert_eq!(offset(text, &at(9, 0)), text.len());
+    }
+
+    #[test]
+    fn lsp_diagnostics() {
+        let found = diagnostics(REPLIT);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0]["message"], "entrypoint should be a string");
+        assert_eq!(found[0]["range"]["start"], at(1, 0));
+        assert_eq!(found[1]["severity"], WARNING);
+        assert_eq!(found[1]["data"]["path"], "nix/chanel");
+
+        let broken = diagnostics("run = \n");
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0]["severity"], ERROR);
+    }
+
+    #[test]
+    fn lsp_hover_and_completion() {
+        let hovered = hover(REPLIT, &at(0, 1));
+        assert!(hovered["contents"]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("**run**"));
+        assert_eq!(hover(REPLIT, &at(4, 2)), Value::Null);
+
+        let text = "run = \"python3 main.py\"\n\n[nix]\nchannel = \n[deployment]\n";
+        let labels = |items: Value| {
+            items
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let values = labels(completion(text, &at(3, 10)));
+        assert_eq!(values[0], "\"stable-24_05\"");
+        let keys = labels(completion(REPLIT, &at(2, 0)));
+        assert!(keys.contains(&"onBoot".to_string()));
+        assert!(!keys.contains(&"run".to_string()));
+        let deployment = labels(completion(&format!("{}dep", text), &at(5, 0)));
+        assert!(deployment.contains(&"deploymentTarget".to_string()));
+    }
+
+    #[test]
+    fn lsp_formatting() {
+        let text = "run   =\"python3 main.py\"   # main  \n\n\n[nix]\nchannel=\"stable-24_05\"\n\n";
+        assert_eq!(
+            format(text).unwrap(),
+            "run = \"python3 main.py\"   # main\n\n[nix]\nchannel = \"stable-24_05\"\n"
+        );
+        // only entries are spaced when a line ends inside a string
+        let text = "notes='''\ntrailing   \n'''\n\n\n";
+        assert_eq!(format(text).unwrap(), "notes = '''\ntrailing   \n'''\n\n\n");
+        assert_eq!(formatting("a = 1\n"), json!([]));
+    }
+
+    #[test]
+    fn lsp_code_actions() {
+        let session = Session::for_test(&std::env::temp_dir());
+        let actions = code_actions(
+            REPLIT,
+            "file:///.replit",
+            &json!(diagnostics(REPLIT)),
+            &session,
+        );
+        let titles = actions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["title"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            titles,
+            [
+                "Remove entrypoint",
+                "Rename nix/chanel to nix/channel",
+                "Remove nix/chanel"
+            ]
+        );
+        assert_eq!(
+            actions[1]["edit"]["changes"]["file:///.replit"][0]["newText"],
+            "run = \"python3 main.py\"\nentrypoint = 1\n\n[nix]\nchannel = \"stable-24_05\"\n"
+        );
+    }
+
+    #[test]
+    fn lsp_session() {
+        let frame = |message: Value| {
+            let message = message.to_string();
+            format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+        };
+        let input = [
+            frame(json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} })),
+            frame(
+                json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": "file:///.replit", "text": REPLIT },
+            } }),
+            ),
+            frame(json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" })),
+            frame(json!({ "jsonrpc": "2.0", "method": "exit" })),
+        ]
+        .concat();
+
+        let mut output = vec![];
+        let code = serve(
+            &mut input.as_bytes(),
+            &mut output,
+            &Session::for_test(&std::env::temp_dir()),
+        )
+        .unwrap();
+        assert_eq!(code, 0);
+
+        let mut output = output.as_slice();
+        let mut read = || {
+            let message = framing::read(&mut output, Framing::ContentLength, 65536).unwrap();
+            from_str::<Value>(&message.unwrap().unwrap()).unwrap()
+        };
+        assert_eq!(read()["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(read()["params"]["diagnostics"].as_array().unwrap().len(), 2);
+        assert_eq!(read()["id"], 2);
+    }
+}
@@ -1,22 +1,66 @@
 mod adder;
+mod backups;
+mod commands;
 mod converter;
+mod differ;
+mod error;
 mod field_finder;
+mod finder;
+mod framing;
+mod history;
+mod journal;
+mod jsonrpc;
+mod key_path;
+mod limits;
+mod lsp;
+mod merger;
+mod policy;
+mod raw;
 mod remover;
+mod schema;
+mod server;
+mod syncer;
+mod target;
+#[cfg(test)]
+mod testing;
 mod traversal;
+mod verifier;
+mod watch;
 
+use std::collections::HashMap;
 use std::fs;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::{io, io::prelude::*};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, json, Value};
+use serde_json::{from_str, from_value, json, Value};
 use toml_edit::DocumentMut;
 
 use crate::adder::handle_add;
+use crate::commands::Output;
+use crate::differ::{handle_diff, PatchOp};
+use crate::error::CodedError;
+use crate::finder::handle_find;
+use crate::framing::Framing;
+use crate::history::{inverse_patch, History, Step};
+use crate::journal::{Caller, Entry};
+use crate::limits::Limits;
+use crate::merger::handle_merge;
+use crate::policy::Policy;
 use crate::remover::handle_remove;
+use crate::syncer::{apply_patch_op, handle_sync};
+use crate::target::{Sandbox, DEFAULT_ALLOW};
 use crate::traversal::TraverseOps;
+use crate::verifier::Model;
+use crate::watch::Watch;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -28,9 +72,167 @@ struct Args {
     // Whether or not to write this value directly to the file,
     // or just print it as part of the return message.
     return_output: bool,
+
+    /// How many batches can be undone
+    #[clap(long, value_parser, default_value = "20")]
+    history: usize,
+
+    /// Appends every batch that changes the file to this journal
+    #[clap(long, value_parser)]
+    journal: Option<PathBuf>,
+
+    /// How many backups of the previous contents to keep, next to the file in
+    /// a directory named after it with a `.bak` suffix. None are taken if 0
+    #[clap(long, value_parser, default_value = "0")]
+    backups: usize,
+
+    /// The directory that files named in requests have to be in
+    #[clap(long, value_parser, default_value = ".")]
+    root: PathBuf,
+
+    /// Glob for the files, relative to --root, that requests may name. Can be
+    /// given multiple times. Defaults to `.replit` and `*.toml` files
+    #[clap(long, value_parser)]
+    allow: Vec<String>,
+
+    /// A TOML file with the paths that requests may not change, see `Policy`
+    #[clap(long, value_parser)]
+    policy: Option<PathBuf>,
+
+    /// The longest request line, in bytes
+    #[clap(long, value_parser, default_value = "1048576")]
+    max_line_bytes: usize,
+
+    /// The largest document that is read or written, in bytes
+    #[clap(long, value_parser, default_value = "1048576")]
+    max_document_bytes: usize,
+
+    /// How deep paths and documents may nest
+    #[clap(long, value_parser, default_value = "64")]
+    max_depth: usize,
+
+    /// The most members an array may have
+    #[clap(long, value_parser, default_value = "10000")]
+    max_array_len: usize,
+
+    /// The most ops a batch may have
+    #[clap(long, value_parser, default_value = "1000")]
+    max_batch_ops: usize,
+
+    /// How requests and responses are encoded
+    #[clap(long, value_enum, default_value = "lines")]
+    protocol: Protocol,
+
+    /// How messages are delimited: by newlines, or by a Content-Length header
+    /// like in the Language Server Protocol
+    #[clap(long, value_enum, default_value = "lines")]
+    framing: Framing,
+
+    /// Applies the ops without writing anything. `set`, `rm`, `apply`,
+    /// `filter` and `replay` then exit with 3 if the document would change,
+    /// and 0 if not, and requests on stdin are handled as dry runs
+    #[clap(long, value_parser, default_value = "false")]
+    check: bool,
+
+    /// Reports changes that others make to the file while requests are read
+    /// from stdin, as notifications with the patch of what changed
+    #[clap(long, value_parser, default_value = "false")]
+    watch: bool,
+
+    /// How often the file is looked at for --watch, in milliseconds
+    #[clap(long, value_parser, default_value = "250")]
+    watch_interval: u64,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Protocol {
+    /// A batch of ops or an envelope per message, answered with a `Res`
+    Lines,
+    /// JSON-RPC 2.0, see `jsonrpc`
+    Jsonrpc,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Applies the batches recorded in a journal to the file, in order
+    Replay { journal: PathBuf },
+
+    /// Prints the value at a path as JSON
+    Get { path: String },
+
+    /// Sets the value at a path, given as JSON, like the `add` op
+    Set { path: String, value: String },
+
+    /// Removes the value at a path
+    Rm { path: String },
+
+    /// Applies a batch of ops from a JSON file, or from stdin if it is `-`,
+    /// and prints their results
+    Apply { patch: PathBuf },
+
+    /// Reads a TOML document from stdin, applies a batch of ops to it and
+    /// prints the edited document, without touching any file
+    Filter {
+        /// The batch of ops as JSON
+        #[clap(required_unless_present = "ops_file")]
+        ops: Option<String>,
+
+        /// A JSON file to read the batch of ops from instead
+        #[clap(long, value_parser, conflicts_with = "ops")]
+        ops_file: Option<PathBuf>,
+    },
+
+    /// Serves the protocol to any number of clients connecting to a
+    /// Unix domain socket, instead of to stdin
+    Serve {
+        #[clap(long, value_parser)]
+        socket: PathBuf,
+    },
+
+    /// Runs a language server for `.replit` files, speaking the Language
+    /// Server Protocol on stdin and stdout
+    Lsp,
+}
+
+// What is kept from one message to the next. It is shared by all clients of a
+// server, so it is only ever borrowed immutably.
+struct Session {
+    // Every file that was edited has a history of its own, which is locked for
+    // as long as a batch edits the file, so that edits to a file are serialized
+    // while different files can be edited in parallel.
+    histories: Mutex<HashMap<PathBuf, Arc<Mutex<History>>>>,
+    history_limit: usize,
+    journal: Option<PathBuf>,
+    backups: usize,
+    sandbox: Sandbox,
+    policy: Option<Policy>,
+    limits: Limits,
+    protocol: Protocol,
+    framing: Framing,
+    watch: Option<Watch>,
+}
+
+impl Session {
+    // Files are told apart by their canonical path, since the same file can be
+    // named as given to --path and as resolved from a request's `path`.
+    fn history(&self, dotreplit_filepath: &Path) -> Arc<Mutex<History>> {
+        lock(&self.histories)
+            .entry(target::canonical(dotreplit_filepath))
+            .or_insert_with(|| Arc::new(Mutex::new(History::new(self.history_limit))))
+            .clone()
+    }
+}
+
+// A panic while a lock was held is reported as an error of its own, see
+// `handle_message`, so the lock can still be used afterwards.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "op")]
 enum OpKind {
     /// Creates the field if it doesn't already exist and sets it
@@ -41,12 +243,92 @@ enum OpKind {
     #[serde(rename = "get")]
     Get { path: String },
 
+    /// Describes the kind of every item below the specified path,
+    /// or the whole document if no path is given
+    #[serde(rename = "describe")]
+    Describe { path: Option<String> },
+
+    /// Lists the keys of the table at the specified path in document order,
+    /// or of the whole document if no path is given
+    #[serde(rename = "keys")]
+    Keys { path: Option<String> },
+
+    /// Gets the length of the array or array of tables at the specified path
+    #[serde(rename = "len")]
+    Len { path: String },
+
+    /// Gets the TOML type of the item at the specified path
+    #[serde(rename = "type")]
+    Type { path: String },
+
+    /// Lists every leaf value below the specified path together with its full path,
+    /// or of the whole document if no path is given
+    #[serde(rename = "flatten")]
+    Flatten { path: Option<String> },
+
+    /// Finds the entries below the specified path whose key matches the `key` glob
+    /// and/or whose value matches the `value` regex, together with their line numbers
+    #[serde(rename = "find")]
+    Find {
+        path: Option<String>,
+        key: Option<String>,
+        value: Option<String>,
+    },
+
     /// Removes the field if it exists
     #[serde(rename = "remove")]
     Remove { path: String },
+
+    /// Gets the TOML source text at the specified path, comments and formatting included
+    #[serde(rename = "get_raw")]
+    GetRaw { path: String },
+
+    /// Parses the value as a TOML fragment and splices it in at the specified path,
+    /// a new key at `index` among the keys of its table if given
+    #[serde(rename = "set_raw")]
+    SetRaw {
+        path: String,
+        value: String,
+        index: Option<usize>,
+    },
+
+    /// Applies the minimal set of changes that makes the specified path, or the
+    /// whole document if no path is given, equal to the value. Returns the patch
+    #[serde(rename = "sync")]
+    Sync { path: Option<String>, value: String },
+
+    /// Replaces the value at the specified path, keeping the formatting around it
+    #[serde(rename = "replace")]
+    Replace { path: String, value: String },
+
+    /// Returns the patch that turns the specified path, or the whole document
+    /// if no path is given, into the same path of the TOML document in value
+    #[serde(rename = "diff")]
+    Diff { path: Option<String>, value: String },
+
+    /// Applies the changes between the TOML documents in base and value, unless
+    /// the file changed the same keys since base. Returns what was applied and the conflicts
+    #[serde(rename = "merge")]
+    Merge { base: String, value: String },
+
+    /// Reverts the last `count` batches (1 if not given). Has to be sent on its own
+    #[serde(rename = "undo")]
+    Undo { count: Option<usize> },
+
+    /// Reapplies the last `count` undone batches (1 if not given). Has to be sent on its own
+    #[serde(rename = "redo")]
+    Redo { count: Option<usize> },
+
+    /// Lists the backups of the file, most recent first
+    #[serde(rename = "backups")]
+    Backups,
+
+    /// Replaces the document with a backup, the most recent one (1) if no generation is given
+    #[serde(rename = "restore")]
+    Restore { generation: Option<usize> },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct AddOp {
     path: Option<String>,
     table_header_path: Option<String>,
@@ -54,11 +336,84 @@ struct AddOp {
     value: Option<String>,
 }
 
+/*
+A line is either a bare batch of ops, or an envelope around one that carries
+an `id` to echo in the response and per-request `options`:
+```
+{ "id": 7, "ops": [{ "op": "get", "path": "run" }], "options": { "dry_run": true } }
+```
+*/
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum Request {
+    Batch(Vec<Value>),
+    // the id is taken out before the request is taken apart
+    Envelope {
+        ops: Vec<Value>,
+        #[serde(default)]
+        options: RequestOptions,
+    },
+}
+
+// the keys of an envelope besides `id`
+const ENVELOPE_KEYS: [&str; 2] = ["ops", "options"];
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RequestOptions {
+    /// The file to edit instead of --path, relative to --root
+    path: Option<PathBuf>,
+    /// Overrides --return-output for this request
+    return_output: Option<bool>,
+    /// Applies the ops and reports their results without writing the file
+    dry_run: Option<bool>,
+    /// Who is asking, which decides what the --policy lets the request change
+    role: Option<String>,
+    /// A TOML document to edit instead of a file, which is returned edited
+    content: Option<String>,
+}
+
+impl RequestOptions {
+    fn return_output(&self, default: bool) -> bool {
+        // edited content can only be returned
+        self.content.is_some() || self.return_output.unwrap_or(default)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct Res {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
     status: String,
     message: Option<String>,
     results: Vec<Value>,
+    // the ops that revert the batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inverse: Option<Vec<Value>>,
+    // whether the batch changed the document, or would have for a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changed: Option<bool>,
+    // set for errors that clients are expected to handle, see `CodedError`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    op: Option<Value>,
+}
+
+impl Res {
+    fn error(err: anyhow::Error) -> Res {
+        let coded = err.downcast_ref::<CodedError>();
+        Res {
+            id: None,
+            status: "error".to_string(),
+            message: Some(err.to_string()),
+            results: vec![],
+            inverse: None,
+            changed: None,
+            code: coded.map(|coded| coded.code.to_string()),
+            op: coded.and_then(|coded| coded.op.clone()),
+        }
+    }
 }
 
 // Reads from stdin a json that describes what operation to
@@ -67,85 +422,1082 @@ struct Res {
 fn main() -> Result<()> {
     let args = Args::parse();
     let dotreplit_filepath = args.path;
+    let return_output = args.return_output;
+    let dry_run = args.check;
 
-    // read line by line from stdin until eof
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let line = line?;
-        let res = handle_message(&dotreplit_filepath, &line, args.return_output);
+    let allow = match args.allow.is_empty() {
+        true => DEFAULT_ALLOW
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect(),
+        false => args.allow,
+    };
+    let interval = Duration::from_millis(args.watch_interval);
+    let watch = args
+        .watch
+        .then(|| Watch::new(&dotreplit_filepath, interval));
+    let session = Session {
+        histories: Mutex::new(HashMap::new()),
+        history_limit: args.history,
+        journal: args.journal,
+        backups: args.backups,
+        sandbox: Sandbox::new(&args.root, &allow)?,
+        policy: args.policy.as_deref().map(Policy::load).transpose()?,
+        limits: Limits {
+            line_bytes: args.max_line_bytes,
+            document_bytes: args.max_document_bytes,
+            depth: args.max_depth,
+            array_len: args.max_array_len,
+            batch_ops: args.max_batch_ops,
+        },
+        protocol: args.protocol,
+        framing: args.framing,
+        watch,
+    };
+    if args.watch && args.command.is_some() {
+        bail!("--watch only works with requests on stdin");
+    }
 
-        let res_json = serde_json::to_string(&res)?;
-        println!("{}", res_json);
+    match args.command {
+        Some(Command::Serve { socket }) => server::serve(
+            &socket,
+            &dotreplit_filepath,
+            return_output,
+            args.check,
+            session,
+        ),
+        Some(Command::Lsp) => {
+            let code = lsp::serve(&mut io::stdin().lock(), &mut io::stdout(), &session)?;
+            process::exit(code);
+        }
+        Some(command) => {
+            let output = match (args.check, return_output) {
+                (true, _) => Output::Check,
+                (false, true) => Output::Print,
+                (false, false) => Output::Write,
+            };
+            let code = commands::run(command, &dotreplit_filepath, output, &session);
+            process::exit(code);
+        }
+        // read line by line from stdin until eof
+        None => {
+            // notifications are written between responses, never into one
+            let stdout = io::stdout();
+            let stop = AtomicBool::new(false);
+            thread::scope(|scope| {
+                if let Some(watch) = &session.watch {
+                    let (stop, stdout, session) = (&stop, &stdout, &session);
+                    scope.spawn(move || {
+                        if let Err(err) = watch::run(watch, stop, &mut &*stdout, session) {
+                            eprintln!("error: watching {:?}: {:#}", watch.file, err);
+                        }
+                    });
+                }
+                let served = serve_messages(
+                    &mut io::stdin().lock(),
+                    &mut &stdout,
+                    &dotreplit_filepath,
+                    return_output,
+                    dry_run,
+                    &session,
+                );
+                stop.store(true, Ordering::Relaxed);
+                served
+            })
+        }
     }
+}
+
+// answers every message read in the protocol of the session, until the end of the input
+fn serve_messages(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<()> {
+    loop {
+        let (message, unframed) =
+            match framing::read(reader, session.framing, session.limits.line_bytes) {
+                Ok(Some(message)) => (message, None),
+                Ok(None) => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    (Err(anyhow!("{}", err)), Some(err))
+                }
+                Err(err) => return Err(err.into()),
+            };
+        let response = match session.protocol {
+            Protocol::Lines => {
+                let res = match message {
+                    Ok(message) => handle_message(
+                        dotreplit_filepath,
+                        &message,
+                        return_output,
+                        dry_run,
+                        session,
+                    ),
+                    Err(err) => Res::error(err),
+                };
+                Some(serde_json::to_string(&res)?)
+            }
+            Protocol::Jsonrpc => {
+                jsonrpc::handle(message, dotreplit_filepath, return_output, dry_run, session)
+            }
+        };
 
-    Ok(())
+        if let Some(response) = response {
+            framing::write(writer, session.framing, &response)?;
+        }
+        // nothing after badly framed headers can be read reliably, so the
+        // client is told why and the input is given up on
+        if let Some(err) = unframed {
+            return Err(err.into());
+        }
+    }
 }
 
-fn handle_message(dotreplit_filepath: &Path, msg: &str, return_output: bool) -> Res {
-    match do_edits(dotreplit_filepath, msg, return_output) {
-        Ok((doc, outs)) => Res {
+fn handle_message(
+    dotreplit_filepath: &Path,
+    msg: &str,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Res {
+    // the id is picked out on its own so that it is echoed even if the rest is malformed
+    let mut request: Value = match from_str(msg) {
+        Ok(request) => request,
+        Err(err) => return Res::error(err.into()),
+    };
+    let id = request
+        .as_object_mut()
+        .and_then(|request| request.remove("id"));
+
+    let (ops, options) = match Request::deserialize(&request) {
+        Ok(Request::Batch(ops)) => (ops, RequestOptions::default()),
+        Ok(Request::Envelope { ops, options }) => (ops, options),
+        Err(_) => {
+            // an untagged enum only tells that nothing matched, so misspelled
+            // keys and options are looked for on their own
+            let unknown = request.as_object().and_then(|request| {
+                request
+                    .keys()
+                    .find(|key| !ENVELOPE_KEYS.contains(&key.as_str()))
+            });
+            let err = match (
+                unknown,
+                request.get("options").map(RequestOptions::deserialize),
+            ) {
+                (Some(key), _) => {
+                    anyhow!("unknown field `{}`, expected `id`, `ops` or `options`", key)
+                }
+                (None, Some(Err(err))) => anyhow!("invalid options: {}", err),
+                _ => anyhow!("expected an array of ops or an object with \"ops\""),
+            };
+            return Res {
+                id,
+                ..Res::error(err)
+            };
+        }
+    };
+    let result = handle_request(
+        dotreplit_filepath,
+        ops,
+        &options,
+        id.as_ref(),
+        return_output,
+        dry_run,
+        session,
+    );
+    let return_output = options.return_output(return_output);
+
+    match result {
+        Ok(edited) => Res {
+            id,
             status: "success".to_string(),
-            message: if return_output { Some(doc) } else { None },
-            results: outs,
+            message: if return_output {
+                Some(edited.contents)
+            } else {
+                None
+            },
+            results: edited.outputs,
+            inverse: Some(edited.inverse),
+            changed: Some(edited.changed),
+            code: None,
+            op: None,
         },
         Err(err) => Res {
-            status: "error".to_string(),
-            message: Some(err.to_string()),
-            results: vec![],
+            id,
+            ..Res::error(err)
         },
     }
 }
 
+// what a batch did to the document
+struct Edited {
+    // the edited document, if it was asked for or isn't written
+    contents: String,
+    outputs: Vec<Value>,
+    // the ops that revert the batch
+    inverse: Vec<Value>,
+    // whether the text of the document changed, or would have if it was written
+    changed: bool,
+}
+
+// Applies the ops of a request, with its options taking precedence over the
+// defaults given on the command line, whichever protocol it was sent in.
+fn handle_request(
+    dotreplit_filepath: &Path,
+    ops: Vec<Value>,
+    options: &RequestOptions,
+    id: Option<&Value>,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<Edited> {
+    session.limits.check_batch(&ops)?;
+    let target = options
+        .path
+        .as_deref()
+        .map(|path| session.sandbox.resolve(path))
+        .transpose()?;
+    if target.is_some() && options.content.is_some() {
+        bail!("a request can either name a file in path or send content, not both");
+    }
+    let dotreplit_filepath = target.as_deref().unwrap_or(dotreplit_filepath);
+    let return_output = options.return_output(return_output);
+    let dry_run = options.dry_run.unwrap_or(dry_run);
+
+    // a panic only fails the message that caused it, the next line is served as usual
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let caller = Caller {
+            id: id.cloned(),
+            role: options.role.clone(),
+        };
+        match &options.content {
+            Some(content) => edit_content(content, ops, caller.role.as_deref(), session),
+            None => do_edits(
+                dotreplit_filepath,
+                ops,
+                return_output,
+                dry_run,
+                &caller,
+                session,
+            ),
+        }
+    }))
+    .unwrap_or_else(|payload| Err(CodedError::from_panic(payload).into()))
+}
+
 fn do_edits(
     dotreplit_filepath: &Path,
-    msg: &str,
+    ops: Vec<Value>,
     return_output: bool,
-) -> Result<(String, Vec<Value>)> {
-    // parse line as json
-    let json: Vec<OpKind> = from_str(msg)?;
+    dry_run: bool,
+    caller: &Caller,
+    session: &Session,
+) -> Result<Edited> {
+    let json: Vec<OpKind> = from_value(Value::Array(ops.clone()))?;
+
+    // the file is locked from before it is read until after it is written
+    let history = session.history(dotreplit_filepath);
+    let mut history = lock(&history);
 
     // we need to re-read the file each time since the user might manually edit the
     // file and so we need to make sure we have the most up to date version.
+    if let Ok(metadata) = fs::metadata(dotreplit_filepath) {
+        session.limits.check_size(metadata.len())?;
+    }
+    let (dotreplit_contents, mut doc) = read_doc(dotreplit_filepath)?;
+    session.limits.check_document(&doc)?;
+    let policy = session
+        .policy
+        .as_ref()
+        .filter(|policy| !policy.is_privileged(caller.role.as_deref()));
+
+    let step = match json.as_slice() {
+        [OpKind::Undo { count }] => Some((Step::Undo, count.unwrap_or(1))),
+        [OpKind::Redo { count }] => Some((Step::Redo, count.unwrap_or(1))),
+        _ => None,
+    };
+
+    let (outputs, inverse, stepped, applied) = match step {
+        Some((step, count)) => {
+            // each entry is applied like a batch of its own, so that its inverse can be kept
+            let mut stepped = vec![];
+            let mut applied = vec![];
+            for entry in history.peek(step, count) {
+                let mut before = doc.clone();
+                for raw in entry {
+                    let op = from_value(raw.clone())?;
+                    run_op(&mut doc, op, &raw, Some(dotreplit_filepath), policy)?;
+                    applied.push(raw);
+                }
+                stepped.push(inverse_patch(&mut before, &mut doc)?);
+            }
+            // applying the inverses from the last step back repeats all steps
+            let inverse = stepped.iter().rev().flatten().cloned().collect();
+            let outputs = vec![json!(stepped.len())];
+            (outputs, inverse, Some((step, stepped)), applied)
+        }
+        None => {
+            let (outputs, inverse) =
+                apply_batch(&mut doc, json, &ops, Some(dotreplit_filepath), policy)?;
+            (outputs, inverse, None, ops)
+        }
+    };
+
+    let new_contents = doc.to_string();
+    session.limits.check_size(new_contents.len() as u64)?;
+    session.limits.check_document(&doc)?;
+
+    let changed = dotreplit_contents != new_contents;
+    if return_output || dry_run {
+        return Ok(Edited {
+            contents: new_contents,
+            outputs,
+            inverse,
+            changed,
+        });
+    }
+
+    // write the file back to disk
+    if changed {
+        if session.backups > 0 && !dotreplit_contents.is_empty() {
+            backups::save(dotreplit_filepath, &dotreplit_contents, session.backups)?;
+        }
+        fs::write(dotreplit_filepath, &new_contents)
+            .with_context(|| format!("error: writing file: {:?}", &dotreplit_filepath))?;
+        if let Some(watch) = &session.watch {
+            watch.record(dotreplit_filepath, &dotreplit_contents, &new_contents);
+        }
+
+        if let Some(journal) = &session.journal {
+            let entry = Entry::new(
+                dotreplit_filepath,
+                caller,
+                applied,
+                (
+                    &dotreplit_contents,
+                    &mut dotreplit_contents.parse::<DocumentMut>()?,
+                ),
+                (&new_contents, &mut doc),
+            )?;
+            journal::append(journal, &entry)?;
+        }
+    }
+
+    // only what made it to disk can be undone
+    match stepped {
+        Some((step, stepped)) => history.stepped(step, stepped),
+        None => history.record(inverse.clone()),
+    }
+    Ok(Edited {
+        contents: "".to_string(),
+        outputs,
+        inverse,
+        changed,
+    })
+}
+
+// Applies a batch, other than an undo or redo, and checks that the edits came
+// out as intended. Returns the outputs of the ops and the inverse of the batch.
+fn apply_batch(
+    doc: &mut DocumentMut,
+    json: Vec<OpKind>,
+    ops: &[Value],
+    dotreplit_filepath: Option<&Path>,
+    policy: Option<&Policy>,
+) -> Result<(Vec<Value>, Vec<Value>)> {
+    let mut before = doc.clone();
+    let mut model = Model::new(&mut before)?;
+    let mut outputs = vec![];
+    for (op, raw) in json.into_iter().zip(ops) {
+        let output = run_op(doc, op.clone(), raw, dotreplit_filepath, policy)?;
+        model.apply(&op, &output, doc)?;
+        outputs.push(output);
+    }
+    // nothing is written if the edits didn't come out as intended
+    model.verify(doc)?;
+    let inverse = inverse_patch(&mut before, doc)?;
+    Ok((outputs, inverse))
+}
+
+// Edits a document that came with the request instead of from a file, and
+// returns it edited. Nothing is written, so there is no history to undo with
+// and no backups to restore.
+fn edit_content(
+    content: &str,
+    ops: Vec<Value>,
+    role: Option<&str>,
+    session: &Session,
+) -> Result<Edited> {
+    let json: Vec<OpKind> = from_value(Value::Array(ops.clone()))?;
+    if json
+        .iter()
+        .any(|op| matches!(op, OpKind::Undo { .. } | OpKind::Redo { .. }))
+    {
+        bail!("undo and redo can only be used on files");
+    }
+
+    session.limits.check_size(content.len() as u64)?;
+    let mut doc = content
+        .parse::<DocumentMut>()
+        .context("error: parsing content")?;
+    session.limits.check_document(&doc)?;
+
+    let policy = session
+        .policy
+        .as_ref()
+        .filter(|policy| !policy.is_privileged(role));
+    let (outputs, inverse) = apply_batch(&mut doc, json, &ops, None, policy)?;
+
+    let new_contents = doc.to_string();
+    session.limits.check_size(new_contents.len() as u64)?;
+    session.limits.check_document(&doc)?;
+    Ok(Edited {
+        changed: content != new_contents,
+        contents: new_contents,
+        outputs,
+        inverse,
+    })
+}
+
+fn read_doc(dotreplit_filepath: &Path) -> Result<(String, DocumentMut)> {
     let dotreplit_contents = match fs::read_to_string(dotreplit_filepath) {
         Ok(contents) => contents,
         Err(err) if err.kind() == io::ErrorKind::NotFound => "".to_string(), // if .replit doesn't exist start with an empty one
         Err(_) => return Err(anyhow!("error: reading file - {:?}", &dotreplit_filepath)),
     };
 
-    let mut doc = dotreplit_contents
+    let doc = dotreplit_contents
         .parse::<DocumentMut>()
         .with_context(|| format!("error: parsing file - {:?}", &dotreplit_filepath))?;
+    Ok((dotreplit_contents, doc))
+}
 
-    let mut outputs: Vec<Value> = vec![];
-    for op in json {
-        match op {
-            OpKind::Add(op) => {
-                handle_add(&mut doc, op)?;
-                outputs.push(json!("ok"));
-            }
-            OpKind::Get { path } => match traversal::traverse(TraverseOps::Get, &mut doc, &path) {
-                Ok(value) => outputs.push(value.unwrap_or_default()),
-                Err(error) => {
-                    eprintln!("Error processing {}: {}", path, error);
-                    outputs.push(Value::Null)
-                }
-            },
-            OpKind::Remove { path } => {
-                handle_remove(&path, &mut doc)?;
-                outputs.push(json!("ok"));
+// Applies the batches of a journal that were recorded for the file, in order.
+// Batches that were recorded against other contents than what the file has at
+// that point are still applied, but reported to `warnings`, which is stderr on
+// the command line. The file is only written if `write` is set, otherwise the
+// replayed document is returned in `contents`, like for a dry run.
+fn replay(
+    dotreplit_filepath: &Path,
+    journal: &Path,
+    write: bool,
+    warnings: &mut impl Write,
+) -> Result<Edited> {
+    let (dotreplit_contents, mut doc) = read_doc(dotreplit_filepath)?;
+    let target = target::canonical(dotreplit_filepath);
+
+    for (index, entry) in journal::read(journal)?.into_iter().enumerate() {
+        // a journal is shared by every file that requests name in their path
+        if target::canonical(Path::new(&entry.file)) != target {
+            continue;
+        }
+        if journal::hash(&doc.to_string()) != entry.before {
+            writeln!(
+                warnings,
+                "journal entry {} was recorded against different contents of {}",
+                index + 1,
+                entry.file
+            )?;
+        }
+        for op in from_value::<Vec<OpKind>>(Value::Array(entry.ops))? {
+            apply_op(&mut doc, op, Some(dotreplit_filepath))
+                .with_context(|| format!("replaying journal entry {}", index + 1))?;
+        }
+    }
+
+    let new_contents = doc.to_string();
+    let changed = dotreplit_contents != new_contents;
+    if write && changed {
+        fs::write(dotreplit_filepath, &new_contents)
+            .with_context(|| format!("error: writing file: {:?}", &dotreplit_filepath))?;
+    }
+    Ok(Edited {
+        contents: if write { "".to_string() } else { new_contents },
+        outputs: vec![],
+        inverse: vec![],
+        changed,
+    })
+}
+
+// Applies an op, turning a panic into an `internal_error` and a change that the policy
+// doesn't allow into a `policy_violation`, both carrying the op as it was sent. The
+// document may be half edited afterwards, so the batch has to be abandoned.
+fn run_op(
+    doc: &mut DocumentMut,
+    op: OpKind,
+    raw: &Value,
+    dotreplit_filepath: Option<&Path>,
+    policy: Option<&Policy>,
+) -> Result<Value> {
+    let before = match policy {
+        Some(_) => Some(traversal::to_json(doc)?),
+        None => None,
+    };
+
+    let output = match panic::catch_unwind(AssertUnwindSafe(|| {
+        apply_op(doc, op, dotreplit_filepath)
+    })) {
+        Ok(result) => result?,
+        Err(payload) => return Err(CodedError::from_panic(payload).with_op(raw.clone()).into()),
+    };
+
+    if let (Some(policy), Some(before)) = (policy, before) {
+        let after = traversal::to_json(doc)?;
+        policy
+            .check(&before, &after)
+            .map_err(|err| err.with_op(raw.clone()))?;
+    }
+    Ok(output)
+}
+
+fn apply_op(doc: &mut DocumentMut, op: OpKind, dotreplit_filepath: Option<&Path>) -> Result<Value> {
+    let output = match op {
+        OpKind::Add(op) => {
+            handle_add(doc, op)?;
+            json!("ok")
+        }
+        OpKind::Get { path } => query(TraverseOps::Get, doc, &path),
+        OpKind::Describe { path } => query(TraverseOps::Describe, doc, &path.unwrap_or_default()),
+        OpKind::Keys { path } => query(TraverseOps::Keys, doc, &path.unwrap_or_default()),
+        OpKind::Len { path } => query(TraverseOps::Len, doc, &path),
+        OpKind::Type { path } => query(TraverseOps::Type, doc, &path),
+        OpKind::Flatten { path } => query(TraverseOps::Flatten, doc, &path.unwrap_or_default()),
+        OpKind::Find { path, key, value } => handle_find(
+            doc,
+            &path.unwrap_or_default(),
+            key.as_deref(),
+            value.as_deref(),
+        )?,
+        OpKind::Remove { path } => {
+            handle_remove(&path, doc)?;
+            json!("ok")
+        }
+        OpKind::GetRaw { path } => match raw::get_raw(doc, &path) {
+            Ok(value) => json!(value),
+            Err(error) => {
+                eprintln!("Error processing {}: {}", path, error);
+                Value::Null
             }
+        },
+        OpKind::SetRaw { path, value, index } => {
+            raw::set_raw(doc, &path, &value, index)?;
+            json!("ok")
+        }
+        OpKind::Sync { path, value } => {
+            let patch = handle_sync(doc, &path.unwrap_or_default(), &value)?;
+            Value::Array(patch.iter().map(|op| op.to_json()).collect())
+        }
+        OpKind::Replace { path, value } => {
+            let op = PatchOp::Replace {
+                path: key_path::split(&path),
+                value: from_str(&value).context("parsing value field in replace request")?,
+            };
+            apply_patch_op(doc, &op)?;
+            json!("ok")
+        }
+        OpKind::Diff { path, value } => {
+            let patch = handle_diff(doc, &path.unwrap_or_default(), &value)?;
+            Value::Array(patch.iter().map(|op| op.to_json()).collect())
+        }
+        OpKind::Merge { base, value } => handle_merge(doc, &base, &value)?,
+        OpKind::Backups => backups::list(backed_up(dotreplit_filepath)?)?,
+        OpKind::Restore { generation } => {
+            *doc = backups::load(backed_up(dotreplit_filepath)?, generation.unwrap_or(1))?
+                .parse::<DocumentMut>()
+                .context("parsing backup")?;
+            json!("ok")
         }
+        OpKind::Undo { .. } | OpKind::Redo { .. } => {
+            bail!("undo and redo have to be sent as the only op of a batch")
+        }
+    };
+    Ok(output)
+}
+
+// backups are only kept of files, not of documents sent with the request
+fn backed_up(dotreplit_filepath: Option<&Path>) -> Result<&Path> {
+    dotreplit_filepath.context("backups are only kept of files")
+}
+
+// runs a read-only traversal, reporting failures as null so that
+// the rest of the batch still gets applied
+fn query(op: TraverseOps, doc: &mut DocumentMut, path: &str) -> Value {
+    match traversal::traverse(op, doc, path) {
+        Ok(value) => value.unwrap_or_default(),
+        Err(error) => {
+            eprintln!("Error processing {}: {}", path, error);
+            Value::Null
+        }
+    }
+}
+
+#[cfg(test)]
+mod main_tests {
+    use super::*;
+
+    use crate::testing::scratch_file;
+
+    const REPLIT: &str = "run = \"python3 main.py\"\n";
+
+    fn send(file: &Path, message: &str, session: &Session) -> Value {
+        serde_json::to_value(handle_message(file, message, false, false, session)).unwrap()
     }
 
-    if return_output {
-        return Ok((doc.to_string(), outputs));
+    #[test]
+    fn request_envelopes() {
+        let file = scratch_file("envelopes", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let get = r#"[{ "op": "get", "path": "run" }]"#;
+
+        let res = send(&file, get, &session);
+        assert_eq!(res["results"], json!(["python3 main.py"]));
+        assert!(res.get("id").is_none());
+
+        // ids of any kind are echoed, also for requests that can't be handled
+        for id in [json!(7), json!("req-7"), json!({ "n": 7 }), json!(null)] {
+            let message = json!({ "id": id, "ops": [{ "op": "get", "path": "run" }] });
+            let res = send(&file, &message.to_string(), &session);
+            assert_eq!(res["id"], id);
+            assert_eq!(res["results"], json!(["python3 main.py"]));
+        }
+        let res = send(&file, r#"{ "id": "bad", "ops": "get" }"#, &session);
+        assert_eq!(
+            (&res["id"], &res["status"]),
+            (&json!("bad"), &json!("error"))
+        );
+
+        // a misspelled option fails the request instead of being ignored
+        let add = r#"{ "ops": [{ "op": "add", "path": "audio", "value": "true" }], "options": { "dryrun": true } }"#;
+        let res = send(&file, add, &session);
+        assert_eq!(res["status"], "error");
+        assert!(res["message"]
+            .as_str()
+            .unwrap()
+            .contains("unknown field `dryrun`"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), REPLIT);
+
+        // and so do misspelled or misplaced keys of the envelope
+        for misplaced in [
+            r#"{ "ops": [{ "op": "add", "path": "audio", "value": "true" }], "dry_run": true }"#,
+            r#"{ "ops": [{ "op": "add", "path": "audio", "value": "true" }], "option": { "dry_run": true } }"#,
+        ] {
+            let res = send(&file, misplaced, &session);
+            assert_eq!(res["status"], "error");
+            assert!(res["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("unknown field `"));
+            assert_eq!(fs::read_to_string(&file).unwrap(), REPLIT);
+        }
+
+        let dry_run = add.replace("dryrun", "dry_run");
+        let res = send(&file, &dry_run, &session);
+        assert_eq!(
+            (&res["status"], &res["changed"]),
+            (&json!("success"), &json!(true))
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), REPLIT);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    // write the file back to disk
-    let new_contents = doc.to_string();
-    if dotreplit_contents != new_contents {
-        fs::write(dotreplit_filepath, new_contents)
-            .with_context(|| format!("error: writing file: {:?}", &dotreplit_filepath))?;
+    #[test]
+    fn request_return_output_override() {
+        let file = scratch_file("return-output", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let add = |return_output: bool| {
+            json!({
+                "ops": [{ "op": "add", "path": "audio", "value": "true" }],
+                "options": { "return_output": return_output },
+            })
+            .to_string()
+        };
+
+        // returned instead of written
+        let res = handle_message(&file, &add(true), false, false, &session);
+        assert_eq!(res.message.unwrap(), format!("{}audio = true\n", REPLIT));
+        assert_eq!(fs::read_to_string(&file).unwrap(), REPLIT);
+
+        // written although --return-output is set
+        let res = handle_message(&file, &add(false), true, false, &session);
+        assert_eq!(res.message, None);
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            format!("{}audio = true\n", REPLIT)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serve_stops_at_unframed_messages() {
+        let file = scratch_file("unframed", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session {
+            framing: Framing::ContentLength,
+            ..Session::for_test(&dir)
+        };
+        let get = r#"[{ "op": "get", "path": "run" }]"#;
+        let input = format!(
+            "Content-Length: 3x\r\n\r\n{get}Content-Length: {n}\r\n\r\n{get}Content-Length: {n}\r\n\r\n{get}",
+            get = get,
+            n = get.len()
+        );
+
+        let mut output = vec![];
+        let served = serve_messages(
+            &mut input.as_bytes(),
+            &mut output,
+            &file,
+            false,
+            false,
+            &session,
+        );
+        assert!(served.is_err());
+        // the client is told before the input is given up on
+        let mut output = output.as_slice();
+        let response = framing::read(&mut output, Framing::ContentLength, 65536)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let response: Value = from_str(&response).unwrap();
+        assert_eq!(response["status"], "error");
+        assert_eq!(response["message"], "invalid header: Content-Length: 3x");
+        assert!(framing::read(&mut output, Framing::ContentLength, 65536)
+            .unwrap()
+            .is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn request_panics_are_isolated() {
+        let contents = "run = \"python3 main.py\"\nports = []\n";
+        let file = scratch_file("panics", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+
+        // toml_edit panics when removing past the end of an array
+        let remove = json!({ "op": "remove", "path": "ports/0" });
+        let batch = json!([{ "op": "add", "path": "audio", "value": "true" }, remove]);
+        let res = send(&file, &batch.to_string(), &session);
+        assert_eq!(res["status"], "error");
+        assert_eq!(res["code"], "internal_error");
+        assert_eq!(res["op"], remove);
+        assert_eq!(fs::read_to_string(&file).unwrap(), contents);
+
+        let res = send(&file, r#"[{ "op": "get", "path": "run" }]"#, &session);
+        assert_eq!(res["status"], "success");
+        assert_eq!(res["results"], json!(["python3 main.py"]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn edit(file: &Path, ops: Value, session: &Session) -> Result<Edited> {
+        let ops = ops.as_array().unwrap().clone();
+        do_edits(file, ops, false, false, &Caller::default(), session)
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let contents = "run = \"python3 main.py\"\n\n# the nix channel\n[nix]\nchannel = \"stable-23_05\" # pinned\n";
+        let file = scratch_file("undo", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+        let read = || fs::read_to_string(&file).unwrap();
+        let add = json!([{ "op": "add", "path": "audio", "value": "true" }]);
+        let remove = json!([{ "op": "remove", "path": "nix" }]);
+
+        edit(&file, add.clone(), &session).unwrap();
+        let added = read();
+        let undone = edit(&file, json!([{ "op": "undo" }]), &session).unwrap();
+        assert_eq!(undone.outputs, [json!(1)]);
+        assert_eq!(read(), contents);
+
+        let redone = edit(&file, json!([{ "op": "redo" }]), &session).unwrap();
+        assert_eq!(redone.outputs, [json!(1)]);
+        assert_eq!(read(), added);
+        // nothing is left to redo
+        let redone = edit(&file, json!([{ "op": "redo" }]), &session).unwrap();
+        assert_eq!(redone.outputs, [json!(0)]);
+
+        // the table comes back with its comments
+        edit(&file, remove, &session).unwrap();
+        edit(&file, json!([{ "op": "undo" }]), &session).unwrap();
+        assert_eq!(read(), added);
+
+        // undoing more than there is stops at the first batch
+        let undone = edit(&file, json!([{ "op": "undo", "count": 5 }]), &session).unwrap();
+        assert_eq!(undone.outputs, [json!(1)]);
+        assert_eq!(read(), contents);
+
+        let err = edit(&file, json!([{ "op": "undo" }, add[0]]), &session)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "undo and redo have to be sent as the only op of a batch"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_removed_keys_in_place() {
+        let contents = "# how to start\nrun = \"x\"  # entry\nb = 1\n\n[nix]\n# pinned\nchannel = \"stable-23_05\"\npackages = []\n";
+        let file = scratch_file("undo-keys", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+
+        for path in ["run", "nix/channel"] {
+            let remove = json!([{ "op": "remove", "path": path }]);
+            edit(&file, remove, &session).unwrap();
+            assert_ne!(fs::read_to_string(&file).unwrap(), contents);
+            edit(&file, json!([{ "op": "undo" }]), &session).unwrap();
+            assert_eq!(fs::read_to_string(&file).unwrap(), contents);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn get_leaves_formatting_alone() {
+        let contents = "run = 'python3 main.py'\nenv = { PATH = '/bin' }\n";
+        let file = scratch_file("get-formatting", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+
+        let ops = json!([
+            { "op": "get", "path": "run" },
+            { "op": "get", "path": "env" },
+            { "op": "add", "path": "audio", "value": "true" },
+        ]);
+        let edited = edit(&file, ops, &session).unwrap();
+        assert_eq!(
+            edited.outputs[..2],
+            [json!("python3 main.py"), json!({ "PATH": "/bin" })]
+        );
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            contents.to_string() + "audio = true\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn paths_unescape_keys() {
+        let contents = "[env]\n\"a/b\" = \"x\"\n\"HOME~1\" = \"y\"\n";
+        let file = scratch_file("paths-escaped", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session::for_test(&dir);
+
+        let ops = json!([
+            { "op": "get", "path": "env/a~1b" },
+            { "op": "get", "path": "env/HOME~01" },
+            // "~1" is "/", so this is the key "HOME/" and not "HOME~1"
+            { "op": "get", "path": "env/HOME~1" },
+            { "op": "add", "path": "env/c~0d", "value": "\"z\"" },
+            { "op": "remove", "path": "env/a~1b" },
+        ]);
+        let edited = edit(&file, ops, &session).unwrap();
+        assert_eq!(edited.outputs[..3], [json!("x"), json!("y"), Value::Null]);
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "[env]\n\"HOME~1\" = \"y\"\n\"c~d\" = \"z\"\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn undo_history_is_bounded() {
+        let file = scratch_file("undo-bounded", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let session = Session {
+            history_limit: 2,
+            ..Session::for_test(&dir)
+        };
+
+        for key in ["audio", "disableGuessImports", "disableInstallBeforeRun"] {
+            let add = json!([{ "op": "add", "path": key, "value": "true" }]);
+            edit(&file, add, &session).unwrap();
+        }
+        let undone = edit(&file, json!([{ "op": "undo", "count": 3 }]), &session).unwrap();
+        assert_eq!(undone.outputs, [json!(2)]);
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            format!("{}audio = true\n", REPLIT)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backups_and_restore() {
+        // the first version has a comment and odd spacing that have to survive
+        let contents = "run   = \"python3 main.py\"  # start\n";
+        let file = scratch_file("restore", contents);
+        let dir = file.parent().unwrap().to_path_buf();
+        let journal = dir.join("journal.jsonl");
+        let session = Session {
+            backups: 2,
+            journal: Some(journal.clone()),
+            ..Session::for_test(&dir)
+        };
+        let read = || fs::read_to_string(&file).unwrap();
+
+        let mut versions = vec![read()];
+        for key in ["audio", "disableGuessImports", "disableInstallBeforeRun"] {
+            let add = json!([{ "op": "add", "path": key, "value": "true" }]);
+            edit(&file, add, &session).unwrap();
+            versions.push(read());
+        }
+        // every write took a generation, but only the last two are kept
+        let listed = edit(&file, json!([{ "op": "backups" }]), &session).unwrap();
+        assert_eq!(listed.outputs[0].as_array().unwrap().len(), 2);
+        assert_eq!(backups::load(&file, 1).unwrap(), versions[2]);
+        assert_eq!(backups::load(&file, 2).unwrap(), versions[1]);
+
+        let restore = json!([{ "op": "restore", "generation": 2 }]);
+        edit(&file, restore.clone(), &session).unwrap();
+        assert_eq!(read(), versions[1]);
+        let entries = journal::read(&journal).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(Value::Array(entries[3].ops.clone()), restore);
+
+        edit(&file, json!([{ "op": "undo" }]), &session).unwrap();
+        assert_eq!(read(), versions[3]);
+
+        let missing = json!([{ "op": "restore", "generation": 3 }]);
+        let err = edit(&file, missing, &session).err().unwrap();
+        assert_eq!(err.to_string(), "there is no backup generation 3");
+        assert_eq!(read(), versions[3]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn journal_records_callers_and_replays() {
+        let file = scratch_file("replay", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let journal = dir.join("journal.jsonl");
+        let session = Session {
+            journal: Some(journal.clone()),
+            ..Session::for_test(&dir)
+        };
+
+        let add = r#"{ "id": "req-1", "ops": [{ "op": "add", "path": "audio", "value": "true" }],
+            "options": { "role": "agent" } }"#;
+        assert_eq!(send(&file, add, &session)["status"], "success");
+        // a later batch depends on the earlier one, so the order has to be kept
+        let replace = r#"[{ "op": "replace", "path": "audio", "value": "false" }]"#;
+        assert_eq!(send(&file, replace, &session)["status"], "success");
+        let edited = fs::read_to_string(&file).unwrap();
+
+        let entries = journal::read(&journal).unwrap();
+        assert_eq!(entries[0].caller.id, Some(json!("req-1")));
+        assert_eq!(entries[0].caller.role.as_deref(), Some("agent"));
+        assert!(entries[1].caller.id.is_none() && entries[1].caller.role.is_none());
+
+        // the journal is replayed onto the file that it was recorded for, reset
+        let replay_into = |contents: &str| {
+            fs::write(&file, contents).unwrap();
+            let mut warnings = vec![];
+            replay(&file, &journal, true, &mut warnings).unwrap();
+            String::from_utf8(warnings).unwrap()
+        };
+
+        assert_eq!(replay_into(REPLIT), "");
+        assert_eq!(fs::read_to_string(&file).unwrap(), edited);
+
+        // other contents are still replayed onto, but every entry whose
+        // contents don't match what was recorded is reported
+        let warnings = replay_into("run = \"bash main.sh\"\n");
+        assert_eq!(
+            warnings.lines().collect::<Vec<_>>(),
+            [
+                "journal entry 1 was recorded against different contents of ".to_string()
+                    + &entries[0].file,
+                "journal entry 2 was recorded against different contents of ".to_string()
+                    + &entries[1].file,
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "run = \"bash main.sh\"\naudio = false\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_skips_other_files() {
+        let file = scratch_file("replay-files", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        let journal = dir.join("journal.jsonl");
+        let pyproject = dir.join("pyproject.toml");
+        fs::write(&pyproject, "").unwrap();
+        let session = Session {
+            journal: Some(journal.clone()),
+            ..Session::for_test(&dir)
+        };
+
+        // batches for both files end up in the same journal, interleaved
+        let messages = [
+            r#"[{ "op": "add", "path": "audio", "value": "true" }]"#,
+            r#"{ "ops": [{ "op": "add", "path": "project/name", "value": "\"repl\"" }],
+                "options": { "path": "pyproject.toml" } }"#,
+            r#"[{ "op": "add", "path": "hidden", "value": "[\"venv\"]" }]"#,
+            r#"{ "ops": [{ "op": "remove", "path": "project" }],
+                "options": { "path": "pyproject.toml" } }"#,
+        ];
+        for message in messages {
+            assert_eq!(send(&file, message, &session)["status"], "success");
+        }
+        assert_eq!(journal::read(&journal).unwrap().len(), 4);
+        let edited = fs::read_to_string(&file).unwrap();
+
+        fs::write(&file, REPLIT).unwrap();
+        let mut warnings = vec![];
+        replay(&file, &journal, true, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(fs::read_to_string(&file).unwrap(), edited);
+
+        // named another way, it is still the same file
+        fs::write(&pyproject, "").unwrap();
+        replay(&dir.join("./pyproject.toml"), &journal, true, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(fs::read_to_string(&pyproject).unwrap(), "");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn request_paths_share_a_history() {
+        let file = scratch_file("paths", REPLIT);
+        let dir = file.parent().unwrap().to_path_buf();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let session = Session::for_test(&dir);
+        // --path as given, which isn't how the sandbox spells the same file
+        let given = dir.join("sub/../.replit");
+
+        let add = r#"[{ "op": "add", "path": "audio", "value": "true" }]"#;
+        assert_eq!(send(&given, add, &session)["status"], "success");
+        let undo = r#"{ "ops": [{ "op": "undo" }], "options": { "path": ".replit" } }"#;
+        assert_eq!(send(&given, undo, &session)["results"], json!([1]));
+        assert_eq!(fs::read_to_string(&file).unwrap(), REPLIT);
+
+        let redo = r#"[{ "op": "redo" }]"#;
+        assert_eq!(send(&given, redo, &session)["results"], json!([1]));
+        assert_eq!(lock(&session.histories).len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
     }
-    Ok(("".to_string(), outputs))
 }
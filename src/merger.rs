@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value as JValue};
+use toml_edit::DocumentMut;
+
+use crate::differ::{diff, lookup, PatchOp};
+use crate::key_path;
+use crate::syncer::apply_patch_op;
+use crate::traversal::to_json;
+
+/*
+Three-way merge at the key level. `base` is the text the client started from,
+`theirs` is the text the client wants, and the document is what is on disk now.
+Whatever the client changed relative to `base` is applied to the document, unless
+the document changed the same key (or the same array) in a different way since, in
+which case the document wins and the key is reported as a conflict:
+```
+{
+  "applied": [{ "op": "replace", "path": "run", "value": "\"python3 app.py\"" }],
+  "conflicts": [{ "path": "nix/channel", "base": "stable-23_05", "ours": "stable-24_05", "theirs": "unstable" }]
+}
+```
+*/
+pub fn handle_merge(doc: &mut DocumentMut, base: &str, theirs: &str) -> Result<JValue> {
+    let base = to_json(
+        &mut base
+            .parse::<DocumentMut>()
+            .context("parsing base field in merge request")?,
+    )?;
+    let theirs = to_json(
+        &mut theirs
+            .parse::<DocumentMut>()
+            .context("parsing value field in merge request")?,
+    )?;
+    let ours = to_json(doc)?;
+
+    let our_scopes = diff(&base, &ours, &[])
+        .iter()
+        .map(|op| scope(&base, op))
+        .collect::<Vec<_>>();
+
+    // changes to the same array are grouped, since indexes shift under each other's edits
+    let mut groups: Vec<(Vec<String>, Vec<PatchOp>)> = vec![];
+    for op in diff(&base, &theirs, &[]) {
+        let op_scope = scope(&base, &op);
+        match groups.last_mut() {
+            Some((group_scope, ops)) if *group_scope == op_scope => ops.push(op),
+            _ => groups.push((op_scope, vec![op])),
+        }
+    }
+
+    let mut applied = vec![];
+    let mut conflicts = vec![];
+    for (group_scope, ops) in groups {
+        let overlaps = our_scopes.iter().any(|our_scope| {
+            our_scope.starts_with(&group_scope) || group_scope.starts_with(our_scope)
+        });
+        if overlaps {
+            let ours_value = lookup(&ours, &group_scope);
+            let theirs_value = lookup(&theirs, &group_scope);
+            // if both sides made the same change there is nothing to report
+            if ours_value != theirs_value {
+                conflicts.push(json!({
+                    "path": key_path::join(&group_scope),
+                    "base": lookup(&base, &group_scope),
+                    "ours": ours_value,
+                    "theirs": theirs_value,
+                }));
+            }
+            continue;
+        }
+
+        for op in ops {
+            apply_patch_op(doc, &op)?;
+            applied.push(op.to_json());
+        }
+    }
+
+    Ok(json!({ "applied": applied, "conflicts": conflicts }))
+}
+
+// The part of the document that an op competes for: its own path, or the
+// outermost array on the way there.
+fn scope(base: &JValue, op: &PatchOp) -> Vec<String> {
+    let path = op.path();
+
+    let mut current = base;
+    for (depth, segment) in path.iter().enumerate() {
+        match current {
+            JValue::Array(_) => return path[..depth].to_vec(),
+            JValue::Object(map) => match map.get(segment) {
+                Some(inner) => current = inner,
+                None => break,
+            },
+            _ => break,
+        }
+    }
+    path.to_vec()
+}
+
+#[cfg(test)]
+mod merger_tests {
+    use super::*;
+
+    const BASE: &str = r#"run = "python3 main.py"
+hidden = [".cache"]
+
+[nix]
+channel = "stable-23_05"
+"#;
+
+    #[test]
+    fn merge_non_overlapping() {
+        // the file on disk got a new nix package and a comment in the meantime
+        let mut doc = r#"run = "python3 main.py"  # hand edited
+hidden = [".cache"]
+
+[nix]
+channel = "stable-23_05"
+packages = ["cowsay"]
+"#
+        .parse::<DocumentMut>()
+        .unwrap();
+        let theirs = r#"run = "python3 app.py"
+hidden = [".cache"]
+
+[nix]
+channel = "stable-23_05"
+"#;
+
+        let result = handle_merge(&mut doc, BASE, theirs).unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "applied": [{ "op": "replace", "path": "run", "value": "\"python3 app.py\"" }],
+                "conflicts": [],
+            })
+        );
+        assert_eq!(
+            doc.to_string(),
+            r#"run = "python3 app.py"  # hand edited
+hidden = [".cache"]
+
+[nix]
+channel = "stable-23_05"
+packages = ["cowsay"]
+"#
+        );
+    }
+
+    #[test]
+    fn merge_conflicting_key() {
+        let on_disk = BASE.replace("stable-23_05", "stable-24_05");
+        let mut doc = on_disk.parse::<DocumentMut>().unwrap();
+        let theirs = BASE
+            .replace("stable-23_05", "unstable")
+            .replace("main.py", "app.py");
+
+        let result = handle_merge(&mut doc, BASE, &theirs).unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "applied": [{ "op": "replace", "path": "run", "value": "\"python3 app.py\"" }],
+                "conflicts": [{
+                    "path": "nix/channel",
+                    "base": "stable-23_05",
+                    "ours": "stable-24_05",
+                    "theirs": "unstable",
+                }],
+            })
+        );
+        assert!(doc.to_string().contains(r#"channel = "stable-24_05""#));
+    }
+
+    #[test]
+    fn merge_conflicting_array() {
+        let mut doc = BASE
+            .replace(r#"[".cache"]"#, r#"[".cache", "venv"]"#)
+            .parse::<DocumentMut>()
+            .unwrap();
+        let theirs = BASE.replace(r#"[".cache"]"#, r#"[".cache", ".git"]"#);
+
+        let result = handle_merge(&mut doc, BASE, &theirs).unwrap();
+        assert_eq!(
+            result["conflicts"],
+            json!([{
+                "path": "hidden",
+                "base": [".cache"],
+                "ours": [".cache", "venv"],
+                "theirs": [".cache", ".git"],
+            }])
+        );
+    }
+
+    #[test]
+    fn merge_same_change() {
+        let changed = BASE.replace("stable-23_05", "stable-24_05");
+        let mut doc = changed.parse::<DocumentMut>().unwrap();
+
+        let result = handle_merge(&mut doc, BASE, &changed).unwrap();
+        assert_eq!(result, json!({ "applied": [], "conflicts": [] }));
+    }
+}
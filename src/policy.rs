@@ -0,0 +1,236 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use serde_json::Value as JValue;
+use toml_edit::DocumentMut;
+
+use crate::differ::{diff, lookup};
+use crate::error::CodedError;
+use crate::key_path;
+use crate::traversal::to_json;
+
+/*
+A write policy, read from a TOML file of path patterns (see the README for an
+example). Paths that match `read_only` can only be changed by privileged
+callers, those that match `write_once` can be set if missing but not changed
+or removed afterwards, and those that match `privileged_remove` can be changed
+by anyone but only removed by privileged callers. Privileged callers are those
+whose envelope names one of the `privileged_roles`.
+
+Patterns are globs over paths, where `*` stands for a single key and `**` for
+any number of them.
+*/
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    read_only: Vec<String>,
+    write_once: Vec<String>,
+    privileged_remove: Vec<String>,
+    privileged_roles: Vec<String>,
+}
+
+pub struct Policy {
+    read_only: Vec<Pattern>,
+    write_once: Vec<Pattern>,
+    privileged_remove: Vec<Pattern>,
+    privileged_roles: Vec<String>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Policy> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("error: reading policy - {:?}", path))?;
+        Policy::parse(&contents).with_context(|| format!("error: parsing policy - {:?}", path))
+    }
+
+    fn parse(contents: &str) -> Result<Policy> {
+        let mut doc = contents.parse::<DocumentMut>()?;
+        let json = to_json(&mut doc)?;
+        let file: PolicyFile = serde_json::from_value(json)?;
+
+        let patterns = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Policy {
+            read_only: patterns(file.read_only)?,
+            write_once: patterns(file.write_once)?,
+            privileged_remove: patterns(file.privileged_remove)?,
+            privileged_roles: file.privileged_roles,
+        })
+    }
+
+    pub fn is_privileged(&self, role: Option<&str>) -> bool {
+        role.is_some_and(|role| self.privileged_roles.iter().any(|r| r == role))
+    }
+
+    // Checks the change from `before` to `after`, the JSON of the document around
+    // an op. Every path the change touched is checked, including the tables it is
+    // in and everything below it, so that e.g. removing all of `deployment` is
+    // caught by `deployment/**`.
+    pub fn check(&self, before: &JValue, after: &JValue) -> Result<(), CodedError> {
+        let mut touched = BTreeSet::new();
+        for op in diff(before, after, &[]) {
+            let path = op.path();
+            for depth in 1..path.len() {
+                touched.insert(path[..depth].to_vec());
+            }
+            collect_paths(&lookup(before, path), path, &mut touched);
+            collect_paths(&lookup(after, path), path, &mut touched);
+        }
+
+        for path in touched {
+            let old = lookup(before, &path);
+            let new = lookup(after, &path);
+            if old == new {
+                continue;
+            }
+
+            let joined = key_path::join(&path);
+            let violation = if matches(&self.read_only, &joined) {
+                Some("is read-only")
+            } else if matches(&self.write_once, &joined) && !old.is_null() {
+                Some("can only be written once")
+            } else if matches(&self.privileged_remove, &joined) && new.is_null() {
+                Some("can only be removed by privileged callers")
+            } else {
+                None
+            };
+            if let Some(violation) = violation {
+                return Err(CodedError::new(
+                    "policy_violation",
+                    format!("{} {}", joined, violation),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn matches(patterns: &[Pattern], path: &str) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_with(path, options))
+}
+
+// the path itself and every path below it
+fn collect_paths(value: &JValue, path: &[String], paths: &mut BTreeSet<Vec<String>>) {
+    if value.is_null() {
+        return;
+    }
+    paths.insert(path.to_vec());
+
+    let mut child = path.to_vec();
+    match value {
+        JValue::Object(map) => {
+            for (key, inner) in map {
+                child.push(key.clone());
+                collect_paths(inner, &child, paths);
+                child.pop();
+            }
+        }
+        JValue::Array(array) => {
+            for (index, inner) in array.iter().enumerate() {
+                child.push(index.to_string());
+                collect_paths(inner, &child, paths);
+                child.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod policy_tests {
+    use super::*;
+    use serde_json::json;
+
+    const POLICY: &str = r#"
+read_only = ["deployment/**", "hidden"]
+write_once = ["nix/channel"]
+privileged_remove = ["ports/*"]
+privileged_roles = ["workspace"]
+"#;
+
+    fn violation(before: JValue, after: JValue) -> Option<String> {
+        let policy = Policy::parse(POLICY).unwrap();
+        policy.check(&before, &after).err().map(|err| err.message)
+    }
+
+    #[test]
+    fn policy_roles() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert!(policy.is_privileged(Some("workspace")));
+        assert!(!policy.is_privileged(Some("extension")));
+        assert!(!policy.is_privileged(None));
+    }
+
+    #[test]
+    fn policy_read_only() {
+        let before = json!({ "hidden": [".cache"], "deployment": { "run": "a" } });
+        assert_eq!(
+            violation(
+                before.clone(),
+                json!({ "hidden": [".cache", "x"], "deployment": { "run": "a" } })
+            ),
+            Some("hidden is read-only".to_string())
+        );
+        assert_eq!(
+            violation(before.clone(), json!({ "hidden": [".cache"] })),
+            Some("deployment/run is read-only".to_string())
+        );
+        assert_eq!(
+            violation(
+                before,
+                json!({ "hidden": [".cache"], "deployment": { "run": "a" }, "run": "b" })
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn policy_write_once() {
+        assert_eq!(
+            violation(json!({}), json!({ "nix": { "channel": "stable-24_05" } })),
+            None
+        );
+        assert_eq!(
+            violation(
+                json!({ "nix": { "channel": "stable-23_05" } }),
+                json!({ "nix": { "channel": "stable-24_05" } })
+            ),
+            Some("nix/channel can only be written once".to_string())
+        );
+    }
+
+    #[test]
+    fn policy_privileged_remove() {
+        let before = json!({ "ports": [{ "localPort": 80 }, { "localPort": 3000 }] });
+        assert_eq!(
+            violation(
+                before.clone(),
+                json!({ "ports": [{ "localPort": 8080 }, { "localPort": 3000 }] })
+            ),
+            None
+        );
+        assert_eq!(
+            violation(before, json!({ "ports": [{ "localPort": 3000 }] })),
+            Some("ports/1 can only be removed by privileged callers".to_string())
+        );
+    }
+
+    #[test]
+    fn policy_unknown_field() {
+        assert!(Policy::parse("read_olny = []").is_err());
+    }
+}
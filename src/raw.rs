@@ -0,0 +1,554 @@
+use std::{io::Error, io::ErrorKind};
+
+use anyhow::{bail, Context, Result};
+use toml_edit::{DocumentMut, Item, Key, Table, Value};
+
+use crate::adder::add_in_container;
+use crate::field_finder::{get_field, DoInsert, TomlValue};
+use crate::key_path;
+
+/*
+Returns the TOML source text of the item at a path, or of the whole document
+if the path is empty. Values are returned as they are written, e.g. `"baz"  # comment`.
+Tables and arrays of tables are returned as a standalone document containing
+only that item, together with the headers needed to place it, e.g.
+```
+# the nix channel
+[nix]
+channel = "stable-24_05"
+```
+*/
+pub fn get_raw(doc: &DocumentMut, path: &str) -> Result<String> {
+    if path.is_empty() {
+        return Ok(doc.to_string());
+    }
+    let path_split = key_path::split(path);
+
+    // work on a copy that we strip down to the requested item, so that
+    // everything that is rendered keeps its original formatting
+    let mut pruned = doc.clone();
+    match prune_table(pruned.as_table_mut(), &path_split)? {
+        Some(value) => Ok(value),
+        None => Ok(pruned.to_string()),
+    }
+}
+
+// Removes everything from the table that is not on the path. Returns the source
+// of the item if it is a value, or None if the pruned document should be rendered.
+fn prune_table(table: &mut Table, path: &[String]) -> Result<Option<String>> {
+    let (segment, rest) = path.split_first().context("path is empty")?;
+
+    if !table.contains_key(segment) {
+        bail!(Error::new(ErrorKind::NotFound, "Path does not exist"));
+    }
+    table.retain(|key, _| key == segment);
+    table.set_implicit(true);
+
+    match table.get_mut(segment) {
+        Some(Item::Table(inner)) if !rest.is_empty() => prune_table(inner, rest),
+        Some(Item::Table(_)) => Ok(None),
+        Some(Item::ArrayOfTables(array)) => {
+            let (index, rest) = match rest.split_first() {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+            let index = index
+                .parse::<usize>()
+                .context("Could not parse segment as array index")?;
+            if index >= array.len() {
+                bail!(Error::new(ErrorKind::NotFound, "Path does not exist"));
+            }
+
+            let mut current = 0;
+            array.retain(|_| {
+                current += 1;
+                current - 1 == index
+            });
+
+            let inner = array.get_mut(0).context("Could not find array index")?;
+            if rest.is_empty() {
+                Ok(None)
+            } else {
+                prune_table(inner, rest)
+            }
+        }
+        Some(Item::Value(value)) => raw_value(value, rest).map(Some),
+        Some(Item::None) | None => bail!(Error::new(ErrorKind::NotFound, "Path does not exist")),
+    }
+}
+
+fn raw_value(value: &Value, path: &[String]) -> Result<String> {
+    let segment = match path.first() {
+        Some(segment) => segment,
+        None => return Ok(value.to_string().trim().to_string()),
+    };
+
+    let inner = match value {
+        Value::Array(array) => {
+            let index = segment
+                .parse::<usize>()
+                .context("Could not parse segment as array index")?;
+            array.get(index)
+        }
+        Value::InlineTable(table) => table.get(segment),
+        _ => bail!("Unable to index value {:?} with {:?}", value, segment),
+    };
+
+    match inner {
+        Some(inner) => raw_value(inner, &path[1..]),
+        None => bail!(Error::new(ErrorKind::NotFound, "Path does not exist")),
+    }
+}
+
+/*
+Returns the source text of the key at a path together with its value, e.g.
+`# how to start\nrun = "python3 main.py"  # entry`, and where the key is among
+the keys of its table. None if the item isn't a value in a table.
+*/
+pub fn get_raw_key_value(doc: &DocumentMut, path: &str) -> Option<(String, usize)> {
+    let mut path_split = key_path::split(path);
+    let last_field = path_split.pop()?;
+
+    let mut table = doc.as_table();
+    let mut segments = path_split.iter();
+    while let Some(segment) = segments.next() {
+        table = match table.get(segment)? {
+            Item::Table(inner) => inner,
+            Item::ArrayOfTables(array) => array.get(segments.next()?.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    let (key, item) = table.get_key_value(&last_field)?;
+    if !item.is_value() {
+        return None;
+    }
+    let index = table.iter().position(|(key, _)| key == last_field)?;
+    let mut fragment = DocumentMut::new();
+    fragment.insert_formatted(key, item.clone());
+    Some((fragment.to_string(), index))
+}
+
+/*
+Parses a TOML fragment and splices it in at a path. The fragment can be
+- a value, optionally followed by a comment: `["a", "b"] # the list`
+- the key and its value, with the comments above it for a new key: `# pinned\nchannel = "stable-24_05"`
+- the body of a table: `channel = "stable-24_05"`
+- a whole block including its header: `[nix]` or `[[ports]]` followed by its keys
+An empty path replaces the whole document with the fragment. A key that isn't
+in its table yet goes at `index` among the other keys, if given, or at the end.
+*/
+pub fn set_raw(
+    doc: &mut DocumentMut,
+    path: &str,
+    fragment: &str,
+    index: Option<usize>,
+) -> Result<()> {
+    if path.is_empty() {
+        *doc = fragment
+            .parse::<DocumentMut>()
+            .context("parsing value field in set_raw request")?;
+        return Ok(());
+    }
+    let mut path_split = key_path::split(path);
+
+    let (key, item) = parse_fragment(fragment, &path_split)?;
+
+    let last_field = path_split.pop().context("Path is empty")?;
+    let container =
+        get_field(&path_split, &last_field, DoInsert::Yes, doc).context("Could not find field")?;
+
+    match container {
+        TomlValue::Table(table) => {
+            match table.get_mut(&last_field) {
+                // replace in place so that the key keeps its formatting
+                Some(existing) => {
+                    let position = first_position(existing);
+                    *existing = reposition(item, position);
+                }
+                None => {
+                    let key = key.unwrap_or_else(|| Key::new(last_field.as_str()));
+                    table.insert_formatted(&key, reposition(item, None));
+                    if let Some(index) = index {
+                        move_key(table, &last_field, index);
+                    }
+                }
+            }
+            Ok(())
+        }
+        TomlValue::ArrayOfTables(array) => {
+            let position = last_field
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get(index))
+                .and_then(|table| table.position());
+            add_in_container(
+                TomlValue::ArrayOfTables(array),
+                &last_field,
+                reposition(item, position),
+            )
+        }
+        container => match item {
+            Item::Value(mut value) => {
+                // comments are not allowed inside inline tables and arrays
+                value.decor_mut().clear();
+                add_in_container(container, &last_field, Item::Value(value))
+            }
+            _ => bail!("cannot splice a table into an inline value"),
+        },
+    }
+}
+
+// The item of the fragment, and the key it came with if it is a value that
+// was given together with its key.
+fn parse_fragment(fragment: &str, path: &[String]) -> Result<(Option<Key>, Item)> {
+    // values are parsed as the right hand side of a key so that a trailing comment is kept
+    if let Ok(mut doc) = format!("value = {}", fragment.trim()).parse::<DocumentMut>() {
+        if doc.len() == 1 {
+            if let Some(Item::Value(value)) = doc.remove("value") {
+                return Ok((None, Item::Value(value)));
+            }
+        }
+    }
+
+    let doc = fragment
+        .parse::<DocumentMut>()
+        .context("parsing value field in set_raw request")?;
+
+    // a block that repeats the path in its header, e.g. `[nix]` for "nix",
+    // is unwrapped so that it is not nested under itself
+    let mut root = Item::Table(doc.as_table().clone());
+    if let Some((key, item)) = unwrap_path(&root, None, path) {
+        return Ok((key.filter(|_| item.is_value()), item));
+    }
+    // so is a value given with its key, wherever the key is
+    if let Some((key, item)) = path
+        .last()
+        .and_then(|last| unwrap_path(&root, None, std::slice::from_ref(last)))
+        .filter(|(_, item)| item.is_value())
+    {
+        return Ok((key, item));
+    }
+
+    if let Item::Table(table) = &mut root {
+        table.set_implicit(false);
+        table.decor_mut().clear();
+    }
+    Ok((None, root))
+}
+
+fn unwrap_path(item: &Item, key: Option<&Key>, path: &[String]) -> Option<(Option<Key>, Item)> {
+    let segment = match path.first() {
+        Some(segment) => segment,
+        None => return Some((key.cloned(), item.clone())),
+    };
+
+    match item {
+        Item::Table(table) if table.len() == 1 => {
+            let (key, inner) = table.get_key_value(segment)?;
+            unwrap_path(inner, Some(key), &path[1..])
+        }
+        Item::ArrayOfTables(array) if array.len() == 1 && segment.parse::<usize>().is_ok() => {
+            let table = array.get(0)?;
+            match path.get(1) {
+                None => Some((None, Item::Table(table.clone()))),
+                Some(next) if table.len() == 1 => {
+                    let (key, inner) = table.get_key_value(next)?;
+                    unwrap_path(inner, Some(key), &path[2..])
+                }
+                Some(_) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// moves a key to `index` among the keys of its table, keeping how all of them are written
+fn move_key(table: &mut Table, key: &str, index: usize) {
+    let keys = table
+        .iter()
+        .filter_map(|(key, _)| table.key(key).cloned())
+        .collect::<Vec<_>>();
+    let mut entries = keys
+        .into_iter()
+        .filter_map(|key| table.remove(key.get()).map(|item| (key, item)))
+        .collect::<Vec<_>>();
+
+    if let Some(from) = entries.iter().position(|(entry, _)| entry.get() == key) {
+        let entry = entries.remove(from);
+        entries.insert(index.min(entries.len()), entry);
+    }
+    for (key, item) in entries {
+        table.insert_formatted(&key, item);
+    }
+}
+
+fn first_position(item: &Item) -> Option<usize> {
+    match item {
+        Item::Table(table) => table.position(),
+        Item::ArrayOfTables(array) => array.iter().find_map(|table| table.position()),
+        _ => None,
+    }
+}
+
+// Tables parsed from a fragment carry positions from that fragment, which would
+// sort them among unrelated tables of the document. Either move them all to the
+// position of the item they replace or drop the positions so that they are
+// rendered right after their parent.
+fn reposition(item: Item, position: Option<usize>) -> Item {
+    match item {
+        Item::Table(table) => Item::Table(reposition_table(table, position)),
+        Item::ArrayOfTables(array) => Item::ArrayOfTables(
+            array
+                .into_iter()
+                .map(|table| reposition_table(table, position))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn reposition_table(mut table: Table, position: Option<usize>) -> Table {
+    let mut repositioned = Table::new();
+    repositioned.set_implicit(table.is_implicit());
+    repositioned.set_dotted(table.is_dotted());
+    *repositioned.decor_mut() = table.decor().clone();
+    if let Some(position) = position {
+        repositioned.set_position(position);
+    }
+
+    let keys = table
+        .iter()
+        .filter_map(|(key, _)| table.key(key).cloned())
+        .collect::<Vec<_>>();
+    for key in keys {
+        if let Some(item) = table.remove(key.get()) {
+            repositioned.insert_formatted(&key, reposition(item, position));
+        }
+    }
+
+    repositioned
+}
+
+#[cfg(test)]
+mod raw_tests {
+    use super::*;
+
+    const DOTREPLIT_CONTENT: &str = r#"run = "python3 main.py"  # start here
+
+# the nix channel
+[nix]
+channel = "stable-23_05"
+packages = [
+  "python3", # interpreter
+]
+
+[[ports]]
+localPort = 8080
+
+# second port
+[[ports]]
+localPort = 3000
+"#;
+
+    macro_rules! get_raw_test {
+        ($name:ident, $path:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+                let raw = get_raw(&doc, $path).unwrap();
+                assert_eq!(raw.trim(), $expected.trim());
+            }
+        };
+    }
+
+    macro_rules! set_raw_test {
+        ($name:ident, $path:expr, $fragment:expr, $contents:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let mut doc = $contents.parse::<DocumentMut>().unwrap();
+                set_raw(&mut doc, $path, $fragment, None).unwrap();
+                assert_eq!(doc.to_string().trim(), $expected.trim());
+            }
+        };
+    }
+
+    get_raw_test!(get_raw_value, "run", r#""python3 main.py"  # start here"#);
+
+    get_raw_test!(
+        get_raw_array,
+        "nix/packages",
+        r#"
+[
+  "python3", # interpreter
+]"#
+    );
+
+    get_raw_test!(
+        get_raw_table,
+        "nix",
+        r#"
+# the nix channel
+[nix]
+channel = "stable-23_05"
+packages = [
+  "python3", # interpreter
+]"#
+    );
+
+    get_raw_test!(
+        get_raw_array_of_tables_member,
+        "ports/1",
+        r#"
+# second port
+[[ports]]
+localPort = 3000"#
+    );
+
+    #[test]
+    fn set_raw_key_value_at_index() {
+        let mut doc = "a = 1\nb = 2 # two\n\n[nix]\nchannel = \"stable-23_05\"\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        set_raw(
+            &mut doc,
+            "run",
+            "\n# how to start\nrun = \"x\"  # entry\n",
+            Some(1),
+        )
+        .unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "a = 1\n\n# how to start\nrun = \"x\"  # entry\nb = 2 # two\n\n[nix]\nchannel = \"stable-23_05\"\n"
+        );
+        assert_eq!(
+            get_raw_key_value(&doc, "run"),
+            Some(("\n# how to start\nrun = \"x\"  # entry\n".to_string(), 1))
+        );
+        assert_eq!(get_raw_key_value(&doc, "nix"), None);
+
+        // an existing key keeps its place and its comments
+        set_raw(&mut doc, "b", "b = 3", Some(0)).unwrap();
+        assert!(doc.to_string().contains("# entry\nb = 3\n"));
+    }
+
+    #[test]
+    fn raw_whole_document() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        assert_eq!(get_raw(&doc, "").unwrap(), DOTREPLIT_CONTENT);
+
+        set_raw(&mut doc, "", "# replaced\nrun = \"bash\"\n", None).unwrap();
+        assert_eq!(doc.to_string(), "# replaced\nrun = \"bash\"\n");
+        assert!(set_raw(&mut doc, "", "run = ", None).is_err());
+        assert_eq!(doc.to_string(), "# replaced\nrun = \"bash\"\n");
+    }
+
+    #[test]
+    fn get_raw_missing() {
+        let doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        assert!(get_raw(&doc, "nix/missing").is_err());
+    }
+
+    set_raw_test!(
+        set_raw_value_with_comment,
+        "nix/channel",
+        r#""stable-24_05" # bumped"#,
+        DOTREPLIT_CONTENT,
+        r#"
+run = "python3 main.py"  # start here
+
+# the nix channel
+[nix]
+channel = "stable-24_05" # bumped
+packages = [
+  "python3", # interpreter
+]
+
+[[ports]]
+localPort = 8080
+
+# second port
+[[ports]]
+localPort = 3000
+"#
+    );
+
+    set_raw_test!(
+        set_raw_table_block,
+        "nix",
+        r#"
+# pinned
+[nix]
+channel = "stable-24_05"  # keep
+"#,
+        DOTREPLIT_CONTENT,
+        r#"
+run = "python3 main.py"  # start here
+
+# pinned
+[nix]
+channel = "stable-24_05"  # keep
+
+[[ports]]
+localPort = 8080
+
+# second port
+[[ports]]
+localPort = 3000
+"#
+    );
+
+    set_raw_test!(
+        set_raw_new_table_body,
+        "env",
+        r#"# where to look
+PATH = "/bin"
+"#,
+        r#"run = "main.py""#,
+        r#"
+run = "main.py"
+
+[env]
+# where to look
+PATH = "/bin"
+"#
+    );
+
+    set_raw_test!(
+        set_raw_inline_array_element,
+        "nix/packages/1",
+        r#""nodejs" # ignored"#,
+        r#"
+[nix]
+packages = ["python3"]
+"#,
+        r#"
+[nix]
+packages = ["python3", "nodejs"]
+"#
+    );
+
+    set_raw_test!(
+        set_raw_round_trip,
+        "ports/1",
+        "\n# second port\n[[ports]]\nlocalPort = 3001\n",
+        DOTREPLIT_CONTENT,
+        r#"
+run = "python3 main.py"  # start here
+
+# the nix channel
+[nix]
+channel = "stable-23_05"
+packages = [
+  "python3", # interpreter
+]
+
+[[ports]]
+localPort = 8080
+
+# second port
+[[ports]]
+localPort = 3001
+"#
+    );
+}
@@ -4,12 +4,10 @@ use anyhow::{bail, Context, Result};
 use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Table};
 
 use crate::field_finder::{get_field, DoInsert, TomlValue};
+use crate::key_path;
 
 pub fn handle_remove(field: &str, doc: &mut DocumentMut) -> Result<()> {
-    let mut path_split = field
-        .split('/')
-        .map(|s| s.to_string())
-        .collect::<Vec<String>>();
+    let mut path_split = key_path::split(field);
 
     let last_field = path_split.pop().context("path is empty")?;
 
@@ -0,0 +1,344 @@
+use serde_json::Value as JValue;
+
+use crate::key_path;
+
+/*
+The keys of `.replit` that the editor knows about, with what they hold and
+what they are for. It serves the language server, for hover docs, completion
+and checking documents. Only tables that are marked as closed have all of
+their keys listed, anything else may hold keys that aren't listed here.
+*/
+pub struct Field {
+    pub key: &'static str,
+    pub kind: Kind,
+    pub doc: &'static str,
+    // the values that are usually given, offered as completions
+    pub values: &'static [&'static str],
+}
+
+pub enum Kind {
+    String,
+    // a command, either as a string for the shell or as an array of arguments
+    Command,
+    Strings,
+    Bool,
+    Integer,
+    Table {
+        fields: &'static [Field],
+        closed: bool,
+    },
+    // the fields of every table in the array, which is closed
+    ArrayOfTables(&'static [Field]),
+    // a table with keys of any name
+    Map,
+}
+
+impl Kind {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Kind::String => "a string",
+            Kind::Command => "a string or an array of strings",
+            Kind::Strings => "an array of strings",
+            Kind::Bool => "a boolean",
+            Kind::Integer => "an integer",
+            Kind::Table { .. } | Kind::Map => "a table",
+            Kind::ArrayOfTables(_) => "an array of tables",
+        }
+    }
+
+    fn accepts(&self, value: &JValue) -> bool {
+        let strings = |value: &JValue| {
+            value
+                .as_array()
+                .is_some_and(|array| array.iter().all(|value| value.is_string()))
+        };
+        match self {
+            Kind::String => value.is_string(),
+            Kind::Command => value.is_string() || strings(value),
+            Kind::Strings => strings(value),
+            Kind::Bool => value.is_boolean(),
+            Kind::Integer => value.is_i64(),
+            Kind::Table { .. } | Kind::Map => value.is_object(),
+            Kind::ArrayOfTables(_) => value
+                .as_array()
+                .is_some_and(|array| array.iter().all(|value| value.is_object())),
+        }
+    }
+}
+
+const fn field(key: &'static str, kind: Kind, doc: &'static str) -> Field {
+    Field {
+        key,
+        kind,
+        doc,
+        values: &[],
+    }
+}
+
+const NIX: &[Field] = &[Field {
+    key: "channel",
+    kind: Kind::String,
+    doc: "The Nix channel that packages and modules come from",
+    values: &["stable-24_05", "stable-23_11", "stable-23_05", "unstable"],
+}];
+
+const DEPLOYMENT: &[Field] = &[
+    field(
+        "run",
+        Kind::Command,
+        "The command that starts the deployment",
+    ),
+    field(
+        "build",
+        Kind::Command,
+        "The command that builds the deployment",
+    ),
+    Field {
+        key: "deploymentTarget",
+        kind: Kind::String,
+        doc: "Where the deployment runs",
+        values: &["cloudrun", "gce", "static", "scheduled"],
+    },
+    field(
+        "ignorePorts",
+        Kind::Bool,
+        "Whether the deployment doesn't wait for a port to open",
+    ),
+    field(
+        "publicDir",
+        Kind::String,
+        "The directory that a static deployment serves",
+    ),
+];
+
+const PORTS: &[Field] = &[
+    field(
+        "localPort",
+        Kind::Integer,
+        "The port that the Repl listens on",
+    ),
+    field(
+        "externalPort",
+        Kind::Integer,
+        "The port that the local port is exposed as",
+    ),
+    field(
+        "exposeLocalhost",
+        Kind::Bool,
+        "Whether a port bound to localhost is exposed as well",
+    ),
+];
+
+const PACKAGER: &[Field] = &[
+    field(
+        "language",
+        Kind::String,
+        "The language whose packages the packager manages",
+    ),
+    field(
+        "ignoredPackages",
+        Kind::Strings,
+        "Packages that are never installed by guessing imports",
+    ),
+    field(
+        "ignoredPaths",
+        Kind::Strings,
+        "Paths that are not searched for imports",
+    ),
+];
+
+const AUTH: &[Field] = &[
+    field(
+        "pageEnabled",
+        Kind::Bool,
+        "Whether the Repl's pages require logging in",
+    ),
+    field(
+        "buttonEnabled",
+        Kind::Bool,
+        "Whether the Repl's pages show a login button",
+    ),
+];
+
+pub const ROOT: &[Field] = &[
+    field("run", Kind::Command, "The command that the Run button executes"),
+    field("entrypoint", Kind::String, "The file that is opened when the Repl starts, and run by languages that don't need a command"),
+    field("onBoot", Kind::Command, "The command that runs when the Repl boots"),
+    field("compile", Kind::Command, "The command that runs before every run"),
+    field("language", Kind::String, "The language of the Repl"),
+    field("modules", Kind::Strings, "The toolchains the Repl uses, e.g. `python-3.11`"),
+    field("hidden", Kind::Strings, "Files and directories that the file tree doesn't show"),
+    field("audio", Kind::Bool, "Whether system wide audio is enabled"),
+    field("disableGuessImports", Kind::Bool, "Whether packages are not installed by guessing imports"),
+    field("disableInstallBeforeRun", Kind::Bool, "Whether packages are not installed before every run"),
+    field("nix", Kind::Table { fields: NIX, closed: true }, "How Nix provides the Repl's system packages"),
+    field("deployment", Kind::Table { fields: DEPLOYMENT, closed: true }, "How the Repl is deployed"),
+    field("ports", Kind::ArrayOfTables(PORTS), "The ports that are exposed, one table each"),
+    field("env", Kind::Map, "Environment variables for the run command and the shell"),
+    field("packager", Kind::Table { fields: PACKAGER, closed: false }, "How packages are installed"),
+    field("languages", Kind::Map, "Settings for each language, such as its language server"),
+    field("unitTest", Kind::Table { fields: &[], closed: false }, "How unit tests are run"),
+    field("auth", Kind::Table { fields: AUTH, closed: true }, "Whether the Repl's pages require logging in"),
+];
+
+// the field at a path, where indexes into arrays of tables are skipped
+pub fn field_at(path: &[String]) -> Option<&'static Field> {
+    let (last, parents) = path.split_last()?;
+    if last.parse::<usize>().is_ok() {
+        return field_at(parents);
+    }
+    fields_at(parents)?.iter().find(|field| field.key == last)
+}
+
+// the fields of the table at a path, which are those of the document if it is empty
+pub fn fields_at(path: &[String]) -> Option<&'static [Field]> {
+    let mut fields = ROOT;
+    for segment in path {
+        if segment.parse::<usize>().is_ok() {
+            continue;
+        }
+        fields = match fields.iter().find(|field| field.key == segment)?.kind {
+            Kind::Table { fields, .. } | Kind::ArrayOfTables(fields) => fields,
+            _ => return None,
+        };
+    }
+    Some(fields)
+}
+
+pub struct Violation {
+    pub path: Vec<String>,
+    pub message: String,
+    // the key isn't one of the fields of a closed table
+    pub unknown: bool,
+}
+
+// Checks the JSON of a document against the schema.
+pub fn check(json: &JValue) -> Vec<Violation> {
+    let mut violations = vec![];
+    check_table(json, ROOT, false, &mut vec![], &mut violations);
+    violations
+}
+
+fn check_table(
+    json: &JValue,
+    fields: &[Field],
+    closed: bool,
+    path: &mut Vec<String>,
+    violations: &mut Vec<Violation>,
+) {
+    let map = match json.as_object() {
+        Some(map) => map,
+        None => return,
+    };
+
+    for (key, value) in map {
+        path.push(key.clone());
+        match fields.iter().find(|field| field.key == key) {
+            None if closed => violations.push(Violation {
+                path: path.clone(),
+                message: format!("unknown key {}", key_path::join(path)),
+                unknown: true,
+            }),
+            None => {}
+            Some(field) if !field.kind.accepts(value) => violations.push(Violation {
+                path: path.clone(),
+                message: format!(
+                    "{} should be {}",
+                    key_path::join(path),
+                    field.kind.describe()
+                ),
+                unknown: false,
+            }),
+            Some(field) => match field.kind {
+                Kind::Table { fields, closed } => {
+                    check_table(value, fields, closed, path, violations)
+                }
+                Kind::ArrayOfTables(fields) => {
+                    for (index, table) in value.as_array().into_iter().flatten().enumerate() {
+                        path.push(index.to_string());
+                        check_table(table, fields, true, path, violations);
+                        path.pop();
+                    }
+                }
+                _ => {}
+            },
+        }
+        path.pop();
+    }
+}
+
+// A known key of the same table that an unknown key is probably a typo of,
+// i.e. one that it is at most two edits away from.
+pub fn suggest(path: &[String]) -> Option<&'static str> {
+    let (last, parents) = path.split_last()?;
+    fields_at(parents)?
+        .iter()
+        .map(|field| (distance(last, field.key), field.key))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, key)| key)
+}
+
+// the Levenshtein distance, ignoring case
+fn distance(a: &str, b: &str) -> usize {
+    let a = a.to_lowercase().chars().collect::<Vec<_>>();
+    let b = b.to_lowercase().chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(path: &str) -> Vec<String> {
+        key_path::split(path)
+    }
+
+    #[test]
+    fn schema_lookup() {
+        assert_eq!(field_at(&path("nix/channel")).unwrap().key, "channel");
+        assert_eq!(
+            field_at(&path("ports/0/localPort")).unwrap().key,
+            "localPort"
+        );
+        assert_eq!(field_at(&path("ports/0")).unwrap().key, "ports");
+        assert!(field_at(&path("env/PATH")).is_none());
+        assert_eq!(fields_at(&path("ports")).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn schema_check() {
+        let violations = check(&json!({
+            "run": ["python3", "main.py"],
+            "entrypoint": 1,
+            "custom": true,
+            "nix": { "chanel": "stable-24_05" },
+            "ports": [{ "localPort": 80, "externalPort": "80" }],
+            "env": { "PATH": "/bin" },
+        }));
+        let messages = violations
+            .iter()
+            .map(|violation| violation.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "entrypoint should be a string",
+                "unknown key nix/chanel",
+                "ports/0/externalPort should be an integer",
+            ]
+        );
+        assert_eq!(suggest(&violations[1].path), Some("channel"));
+        assert_eq!(suggest(&path("nix/frobnicate")), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::BufReader;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{serve_messages, Session};
+
+/*
+Serves the protocol on a Unix domain socket, for callers that want to
+coordinate their writes instead of spawning an editor each. Every client gets
+a thread of its own and is answered in the order of its messages.
+The session is shared, so edits to the same file wait for each other (see
+`Session::histories`) and the undo history of a file covers all clients.
+*/
+pub fn serve(
+    socket: &Path,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: Session,
+) -> Result<()> {
+    let listener = bind(socket)?;
+    let session = Arc::new(session);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("error: accepting a client: {}", err);
+                continue;
+            }
+        };
+
+        let session = session.clone();
+        let dotreplit_filepath = dotreplit_filepath.to_path_buf();
+        thread::spawn(move || {
+            if let Err(err) = serve_client(
+                stream,
+                &dotreplit_filepath,
+                return_output,
+                dry_run,
+                &session,
+            ) {
+                eprintln!("error: serving a client: {:#}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_client(
+    stream: UnixStream,
+    dotreplit_filepath: &Path,
+    return_output: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    serve_messages(
+        &mut reader,
+        &mut writer,
+        dotreplit_filepath,
+        return_output,
+        dry_run,
+        session,
+    )
+}
+
+// A socket file that is left over from a server that is gone is replaced, but
+// one that a server still listens on is not.
+fn bind(socket: &Path) -> Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("error: another server is listening on {:?}", socket);
+        }
+        fs::remove_file(socket)
+            .with_context(|| format!("error: removing stale socket {:?}", socket))?;
+    }
+    UnixListener::bind(socket).with_context(|| format!("error: binding socket {:?}", socket))
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use toml_edit::DocumentMut;
+
+    use crate::target::Sandbox;
+    use crate::testing::scratch_dir;
+    use crate::traversal::to_json;
+
+    fn connect(socket: &Path) -> UnixStream {
+        for _ in 0..100 {
+            if let Ok(stream) = UnixStream::connect(socket) {
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the server did not come up");
+    }
+
+    #[test]
+    fn serve_concurrent_clients() {
+        let dir = scratch_dir("serve");
+        let socket = dir.join("editor.sock");
+        let file = dir.join(".replit");
+        let session = Session {
+            sandbox: Sandbox::new(&dir, &["*.toml".to_string()]).unwrap(),
+            ..Session::for_test(&dir)
+        };
+        {
+            let (socket, file) = (socket.clone(), file.clone());
+            thread::spawn(move || serve(&socket, &file, false, false, session));
+        }
+
+        // every client adds keys of its own to the same file, none may get lost
+        let clients = (0..4)
+            .map(|client| {
+                let stream = connect(&socket);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    for key in 0..10 {
+                        let ops = json!([{
+                            "op": "add",
+                            "path": format!("client{}/key{}", client, key),
+                            "value": "true",
+                        }]);
+                        writeln!(writer, "{}", ops).unwrap();
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let res: Value = serde_json::from_str(&line).unwrap();
+                        assert_eq!(res["status"], "success");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        let mut doc = fs::read_to_string(&file)
+            .unwrap()
+            .parse::<DocumentMut>()
+            .unwrap();
+        let json = to_json(&mut doc).unwrap();
+        for client in 0..4 {
+            assert_eq!(
+                json[format!("client{}", client)].as_object().unwrap().len(),
+                10
+            );
+        }
+        assert!(bind(&socket).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
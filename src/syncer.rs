@@ -0,0 +1,200 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{from_str, Value as JValue};
+use toml_edit::{DocumentMut, Item};
+
+use crate::adder::handle_add;
+use crate::converter::json_to_toml;
+use crate::differ::{diff, PatchOp};
+use crate::field_finder::{get_field, DoInsert, TomlValue};
+use crate::key_path;
+use crate::remover::handle_remove;
+use crate::traversal::{traverse, TraverseOps};
+use crate::AddOp;
+
+/*
+Reconciles the item at `path` (or the whole document) with the desired JSON `value`.
+Only the differences are applied, so everything that did not change keeps its
+formatting, comments and position. Returns the patch that was applied.
+*/
+pub fn handle_sync(doc: &mut DocumentMut, path: &str, value: &str) -> Result<Vec<PatchOp>> {
+    let desired: JValue = from_str(value).context("parsing value field in sync request")?;
+    let base = match path {
+        "" => vec![],
+        path => key_path::split(path),
+    };
+    if base.is_empty() && !desired.is_object() {
+        bail!("the whole document can only be synced to an object");
+    }
+
+    // a missing item is treated as null, which the diff turns into an add
+    let current = traverse(TraverseOps::Get, doc, path)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    let patch = diff(&current, &desired, &base);
+    for op in &patch {
+        apply_patch_op(doc, op)?;
+    }
+
+    Ok(patch)
+}
+
+pub fn apply_patch_op(doc: &mut DocumentMut, op: &PatchOp) -> Result<()> {
+    match op {
+        PatchOp::Remove { path } => handle_remove(&key_path::join(path), doc),
+        PatchOp::Replace { path, value } if replace_in_place(doc, path, value)? => Ok(()),
+        PatchOp::Add { path, value } | PatchOp::Replace { path, value } => handle_add(
+            doc,
+            AddOp {
+                path: Some(key_path::join(path)),
+                table_header_path: None,
+                dotted_path: None,
+                value: Some(value.to_string()),
+            },
+        ),
+    }
+}
+
+// Swaps a value for a scalar while keeping the whitespace and comments around it.
+// Returns false if the path does not hold a value that can be swapped.
+fn replace_in_place(doc: &mut DocumentMut, path: &[String], value: &JValue) -> Result<bool> {
+    if value.is_object() || value.is_array() {
+        return Ok(false);
+    }
+
+    let mut parent = path.to_vec();
+    let last_field = parent.pop().context("Path is empty")?;
+    let existing = match get_field(&parent, &last_field, DoInsert::No, doc) {
+        Ok(TomlValue::Table(table)) => table
+            .get_mut(&last_field)
+            .and_then(|item| item.as_value_mut()),
+        Ok(TomlValue::InlineTable(table)) => table.get_mut(&last_field),
+        Ok(TomlValue::Array(array)) => last_field
+            .parse::<usize>()
+            .ok()
+            .and_then(move |index| array.get_mut(index)),
+        _ => None,
+    };
+
+    match (existing, json_to_toml(value, true)?) {
+        (Some(existing), Item::Value(mut replacement)) => {
+            *replacement.decor_mut() = existing.decor().clone();
+            *existing = replacement;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod syncer_tests {
+    use super::*;
+
+    const DOTREPLIT_CONTENT: &str = r#"# how to start
+run = "python3 main.py"  # the entrypoint
+hidden = [".cache", "venv"]
+
+[nix]
+# pinned on purpose
+channel = "stable-23_05"
+
+[[ports]]
+localPort = 8080  # web
+externalPort = 80
+"#;
+
+    macro_rules! sync_test {
+        ($name:ident, $path:expr, $value:expr, $contents:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let mut doc = $contents.parse::<DocumentMut>().unwrap();
+                handle_sync(&mut doc, $path, $value).unwrap();
+                assert_eq!(doc.to_string().trim(), $expected.trim());
+            }
+        };
+    }
+
+    sync_test!(
+        sync_unchanged,
+        "",
+        r#"{"run": "python3 main.py", "hidden": [".cache", "venv"], "nix": {"channel": "stable-23_05"}, "ports": [{"localPort": 8080, "externalPort": 80}]}"#,
+        DOTREPLIT_CONTENT,
+        DOTREPLIT_CONTENT
+    );
+
+    sync_test!(
+        sync_minimal_edits,
+        "",
+        r#"{"run": "python3 app.py", "hidden": [".cache"], "nix": {"channel": "stable-23_05", "packages": ["cowsay"]}, "ports": [{"localPort": 3000, "externalPort": 80}, {"localPort": 8080}]}"#,
+        DOTREPLIT_CONTENT,
+        r#"
+# how to start
+run = "python3 app.py"  # the entrypoint
+hidden = [".cache"]
+
+[nix]
+# pinned on purpose
+channel = "stable-23_05"
+packages = ["cowsay"]
+
+[[ports]]
+localPort = 3000  # web
+externalPort = 80
+
+[[ports]]
+localPort = 8080
+"#
+    );
+
+    sync_test!(
+        sync_subtree,
+        "nix",
+        r#"{"channel": "stable-24_05"}"#,
+        DOTREPLIT_CONTENT,
+        r#"
+# how to start
+run = "python3 main.py"  # the entrypoint
+hidden = [".cache", "venv"]
+
+[nix]
+# pinned on purpose
+channel = "stable-24_05"
+
+[[ports]]
+localPort = 8080  # web
+externalPort = 80
+"#
+    );
+
+    sync_test!(
+        sync_missing_subtree,
+        "env",
+        r#"{"PATH": "/bin"}"#,
+        r#"run = "main.py""#,
+        r#"
+run = "main.py"
+
+[env]
+PATH = "/bin"
+"#
+    );
+
+    #[test]
+    fn sync_returns_patch() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let patch = handle_sync(&mut doc, "hidden", r#"[".cache"]"#).unwrap();
+        assert_eq!(
+            patch,
+            vec![PatchOp::Remove {
+                path: key_path::split("hidden/1")
+            }]
+        );
+    }
+
+    #[test]
+    fn sync_document_to_non_object() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        assert!(handle_sync(&mut doc, "", "[]").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+use crate::error::CodedError;
+
+// the files a request may name if no allowlist is given
+pub const DEFAULT_ALLOW: [&str; 2] = ["**/.replit", "**/*.toml"];
+
+/*
+Requests can name the file they edit, e.g. "replit.toml" or "modules/web/config.toml".
+Such paths are taken relative to the root directory and resolved the way the
+file system would, symlinks included. Anything that ends up outside of the root,
+or whose path within the root doesn't match one of the allowed globs, is refused
+with a `forbidden_path` error.
+*/
+pub struct Sandbox {
+    root: PathBuf,
+    allow: Vec<Pattern>,
+}
+
+impl Sandbox {
+    pub fn new(root: &Path, allow: &[String]) -> Result<Sandbox> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("error: resolving root directory {:?}", root))?;
+        let allow = allow
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .context("parsing allowed file patterns")?;
+        Ok(Sandbox { root, allow })
+    }
+
+    pub fn resolve(&self, requested: &Path) -> Result<PathBuf> {
+        let forbidden = |reason: &str| {
+            CodedError::new(
+                "forbidden_path",
+                format!("target file {:?} {}", requested, reason),
+            )
+        };
+
+        let path = self.root.join(requested);
+        // a file that doesn't exist yet is resolved through its directory
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            // writing through a symlink to a missing file would create it wherever it points
+            Err(_) if path.symlink_metadata().is_ok() => {
+                return Err(forbidden("is a symlink to a file that does not exist").into())
+            }
+            Err(_) => match (path.parent(), path.components().next_back()) {
+                (Some(parent), Some(Component::Normal(name))) => parent
+                    .canonicalize()
+                    .map_err(|_| forbidden("is in a directory that does not exist"))?
+                    .join(name),
+                _ => return Err(forbidden("is not a file").into()),
+            },
+        };
+
+        let relative = resolved
+            .strip_prefix(&self.root)
+            .map_err(|_| forbidden("is outside of the root directory"))?;
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        if !self
+            .allow
+            .iter()
+            .any(|pattern| pattern.matches_path_with(relative, options))
+        {
+            return Err(forbidden("is not one of the allowed files").into());
+        }
+        Ok(resolved)
+    }
+}
+
+// The absolute path of a file with symlinks resolved, which tells whether two
+// paths name the same file. A file that doesn't exist yet is resolved through
+// its directory, and a path that can't be resolved at all is kept as it is.
+pub fn canonical(path: &Path) -> PathBuf {
+    if let Ok(resolved) = path.canonicalize() {
+        return resolved;
+    }
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return path.to_path_buf(),
+    };
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod target_tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testing::scratch_dir;
+
+    fn default_allow() -> Vec<String> {
+        DEFAULT_ALLOW
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+
+    fn code(result: Result<PathBuf>) -> &'static str {
+        result.unwrap_err().downcast::<CodedError>().unwrap().code
+    }
+
+    #[test]
+    fn resolve_inside_root() {
+        let dir = scratch_dir("inside");
+        fs::create_dir_all(dir.join("root/modules/web")).unwrap();
+        let sandbox = Sandbox::new(&dir.join("root"), &default_allow()).unwrap();
+        let root = dir.join("root").canonicalize().unwrap();
+
+        assert_eq!(
+            sandbox
+                .resolve(Path::new("./modules/web/replit.toml"))
+                .unwrap(),
+            root.join("modules/web/replit.toml")
+        );
+        assert_eq!(
+            sandbox.resolve(Path::new("modules/../.replit")).unwrap(),
+            root.join(".replit")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_refuses_escapes() {
+        let dir = scratch_dir("escapes");
+        fs::create_dir_all(dir.join("root/modules/web")).unwrap();
+        let sandbox = Sandbox::new(&dir.join("root"), &default_allow()).unwrap();
+
+        assert_eq!(
+            code(sandbox.resolve(Path::new("../.replit"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("/etc/hosts"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("main.py"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("missing/a.toml"))),
+            "forbidden_path"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_follows_symlinks() {
+        let dir = scratch_dir("symlinks");
+        fs::create_dir_all(dir.join("root/modules/web")).unwrap();
+        fs::write(dir.join("outside.toml"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.toml"), dir.join("root/link.toml")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("root/up")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.toml"), dir.join("root/dangling.toml"))
+            .unwrap();
+        let sandbox = Sandbox::new(&dir.join("root"), &default_allow()).unwrap();
+
+        assert_eq!(
+            code(sandbox.resolve(Path::new("link.toml"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("up/new.toml"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("dangling.toml"))),
+            "forbidden_path"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_checks_allowlist() {
+        let dir = scratch_dir("allowlist");
+        fs::create_dir_all(dir.join("root/modules/web")).unwrap();
+        fs::create_dir_all(dir.join("root/modules/web/nested")).unwrap();
+        let sandbox =
+            Sandbox::new(&dir.join("root"), &["modules/*/replit.toml".to_string()]).unwrap();
+
+        assert!(sandbox
+            .resolve(Path::new("modules/web/replit.toml"))
+            .is_ok());
+        assert_eq!(
+            code(sandbox.resolve(Path::new(".replit"))),
+            "forbidden_path"
+        );
+        assert_eq!(
+            code(sandbox.resolve(Path::new("modules/web/nested/replit.toml"))),
+            "forbidden_path"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::framing::Framing;
+use crate::limits::Limits;
+use crate::target::{Sandbox, DEFAULT_ALLOW};
+use crate::{Protocol, Session};
+
+/*
+Fixtures shared by the tests of every module. A test that needs a session
+with other settings changes the fields it cares about, e.g.
+`Session { backups: 2, ..Session::for_test(&dir) }`, so that a new field of
+`Session` only needs a default here.
+*/
+impl Session {
+    pub fn for_test(root: &Path) -> Session {
+        Session {
+            histories: Mutex::new(HashMap::new()),
+            history_limit: 20,
+            journal: None,
+            backups: 0,
+            sandbox: Sandbox::new(root, &DEFAULT_ALLOW.map(String::from)).unwrap(),
+            policy: None,
+            limits: Limits {
+                line_bytes: 65536,
+                document_bytes: 65536,
+                depth: 8,
+                array_len: 100,
+                batch_ops: 8,
+            },
+            protocol: Protocol::Lines,
+            framing: Framing::Lines,
+            watch: None,
+        }
+    }
+}
+
+// an empty directory of its own for a test, named so that tests running in
+// parallel and other processes don't share it
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("toml-editor-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// a `.replit` with the contents in a scratch directory
+pub fn scratch_file(name: &str, contents: &str) -> PathBuf {
+    let file = scratch_dir(name).join(".replit");
+    fs::write(&file, contents).unwrap();
+    file
+}
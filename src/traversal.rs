@@ -1,10 +1,14 @@
 use std::ops::ControlFlow;
 
 use anyhow::{anyhow, Result};
-use serde_json::Map;
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
 use serde_json::Value as Json;
+use serde_json::{json, Map};
 use toml_edit::{Array, ArrayOfTables, DocumentMut, Item, Table, Value};
 
+use crate::key_path;
+
 #[derive(Debug)]
 pub enum At<'a> {
     Array(&'a mut Array),
@@ -16,6 +20,19 @@ pub enum At<'a> {
 
 pub enum TraverseOps {
     Get,
+    Describe,
+    Keys,
+    Len,
+    Type,
+    Flatten,
+    Find(Search),
+}
+
+pub struct Search {
+    // matched against the key of an entry, or against its whole path if it contains a "/"
+    pub key: Option<Pattern>,
+    // matched against the text of scalar values
+    pub value: Option<Regex>,
 }
 
 /*
@@ -32,7 +49,11 @@ pub fn traverse<'a>(
     doc: &'a mut DocumentMut,
     field: &str,
 ) -> Result<Option<Json>> {
-    let split = field.split('/').collect::<Vec<&str>>();
+    // an empty field refers to the whole document
+    let split = match field {
+        "" => vec![],
+        field => key_path::split(field),
+    };
     let mut path = split.as_slice();
     let table = doc.as_table_mut();
 
@@ -54,9 +75,34 @@ pub fn traverse<'a>(
 
     match op {
         TraverseOps::Get => result?.to_value().map(Some),
+        TraverseOps::Describe => result?.describe().map(Some),
+        TraverseOps::Keys => result?.keys().map(Some),
+        TraverseOps::Len => result?.len().map(Some),
+        TraverseOps::Type => Ok(Some(Json::String(result?.kind().to_string()))),
+        TraverseOps::Flatten => {
+            let mut leaves = vec![];
+            result?.flatten(&mut split.clone(), &mut leaves)?;
+            Ok(Some(Json::Array(leaves)))
+        }
+        TraverseOps::Find(search) => {
+            let mut found = vec![];
+            let key = split.last().map(|key| key.to_string());
+            result?.find(&search, key.as_deref(), &mut split.clone(), &mut found)?;
+            Ok(Some(Json::Array(found)))
+        }
     }
 }
 
+// the whole document as JSON
+pub fn to_json(doc: &mut DocumentMut) -> Result<Json> {
+    Ok(traverse(TraverseOps::Get, doc, "")?.unwrap_or_default())
+}
+
+// the JSON of a document's text, or None if it doesn't parse
+pub fn parse_json(contents: &str) -> Option<Json> {
+    to_json(&mut contents.parse::<DocumentMut>().ok()?).ok()
+}
+
 impl At<'_> {
     // TODO: The rewrapping here happens haphazardly.
     // To do this properly, we should either:
@@ -129,6 +175,7 @@ impl At<'_> {
         }
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_value(&mut self) -> Result<serde_json::Value> {
         match self {
             At::Array(arr) => {
@@ -152,10 +199,9 @@ impl At<'_> {
                 Item::Table(table) => At::Table(table).to_value(),
             },
             At::Value(value) => match value {
-                Value::String(s) => {
-                    s.fmt();
-                    Ok(Json::String(s.value().clone()))
-                }
+                // reading must leave the repr alone: a batch that reads a string and
+                // then edits the document would otherwise rewrite 'literal' as "literal"
+                Value::String(s) => Ok(Json::String(s.value().clone())),
                 Value::Integer(i) => Ok(Json::Number(serde_json::Number::from(
                     i.clone().into_value(),
                 ))),
@@ -171,18 +217,463 @@ impl At<'_> {
                 Value::InlineTable(table) => {
                     let inner: Map<String, Json> = table
                         .iter_mut()
-                        .map(|(k, v)| At::Value(v).to_value().map(|v| (k.to_string(), v)))
+                        .map(|(k, v)| At::Value(v).to_value().map(|v| (k.get().to_string(), v)))
                         .collect::<Result<Map<String, Json>>>()?;
                     Ok(Json::Object(inner))
                 }
             },
             At::Table(table) => {
+                // keys are returned as they are, not as they are written: `"a.b"` is `a.b`
                 let inner: Map<String, Json> = table
                     .iter_mut()
-                    .map(|(k, i)| At::Item(i).to_value().map(|v| (k.to_string(), v)))
+                    .map(|(k, i)| At::Item(i).to_value().map(|v| (k.get().to_string(), v)))
                     .collect::<Result<Map<String, Json>>>()?;
                 Ok(Json::Object(inner))
             }
         }
     }
+
+    /*
+    Describes the structure below this point rather than its data, e.g.
+    ```
+    { "kind": "table", "implicit": false, "dotted": false, "position": 1, "children": [
+        { "key": "channel", "kind": "string" }
+    ] }
+    ```
+    */
+    pub fn describe(&mut self) -> Result<Json> {
+        match self {
+            At::Array(arr) => {
+                let items = arr
+                    .iter_mut()
+                    .map(|val| At::Value(val).describe())
+                    .collect::<Result<Vec<Json>>>()?;
+                Ok(json!({ "kind": "array", "items": items }))
+            }
+            At::ArrayOfTables(aar) => {
+                let items = aar
+                    .iter_mut()
+                    .map(|table| At::Table(table).describe())
+                    .collect::<Result<Vec<Json>>>()?;
+                Ok(json!({ "kind": "array_of_tables", "items": items }))
+            }
+            At::Item(item) => match item {
+                Item::None => Ok(json!({ "kind": "none" })),
+                Item::Value(value) => At::Value(value).describe(),
+                Item::ArrayOfTables(aar) => At::ArrayOfTables(aar).describe(),
+                Item::Table(table) => At::Table(table).describe(),
+            },
+            At::Value(value) => match value {
+                Value::Array(arr) => At::Array(arr).describe(),
+                Value::InlineTable(table) => {
+                    let children = table
+                        .iter_mut()
+                        .map(|(k, v)| At::Value(v).describe().map(|node| keyed(&k, node)))
+                        .collect::<Result<Vec<Json>>>()?;
+                    Ok(json!({ "kind": "inline_table", "children": children }))
+                }
+                _ => Ok(json!({ "kind": value_kind(value) })),
+            },
+            At::Table(table) => {
+                let implicit = table.is_implicit();
+                let dotted = table.is_dotted();
+                let position = table.position();
+                let children = table
+                    .iter_mut()
+                    .map(|(k, i)| At::Item(i).describe().map(|node| keyed(&k, node)))
+                    .collect::<Result<Vec<Json>>>()?;
+                Ok(json!({
+                    "kind": "table",
+                    "implicit": implicit,
+                    "dotted": dotted,
+                    "position": position,
+                    "children": children,
+                }))
+            }
+        }
+    }
+
+    /*
+    Collects every leaf below this point as a path and its value, in document order, e.g.
+    `{ "path": "ports/0/localPort", "value": 8080 }`.
+    Empty tables and arrays are leaves as well, so that they show up in the output.
+    */
+    pub fn flatten(&mut self, path: &mut Vec<String>, leaves: &mut Vec<Json>) -> Result<()> {
+        match self {
+            At::Array(arr) if !arr.is_empty() => {
+                for (index, val) in arr.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    At::Value(val).flatten(path, leaves)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::ArrayOfTables(aar) if !aar.is_empty() => {
+                for (index, table) in aar.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    At::Table(table).flatten(path, leaves)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::Item(item) => match item {
+                Item::None => Ok(()),
+                Item::Value(value) => At::Value(value).flatten(path, leaves),
+                Item::ArrayOfTables(aar) => At::ArrayOfTables(aar).flatten(path, leaves),
+                Item::Table(table) => At::Table(table).flatten(path, leaves),
+            },
+            At::Table(table) if !table.is_empty() => {
+                for (k, i) in table.iter_mut() {
+                    path.push(k.get().to_string());
+                    At::Item(i).flatten(path, leaves)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::Value(Value::Array(arr)) if !arr.is_empty() => At::Array(arr).flatten(path, leaves),
+            At::Value(Value::InlineTable(table)) if !table.is_empty() => {
+                for (k, v) in table.iter_mut() {
+                    path.push(k.get().to_string());
+                    At::Value(v).flatten(path, leaves)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            _ => {
+                let value = self.to_value()?;
+                leaves.push(json!({ "path": key_path::join(path), "value": value }));
+                Ok(())
+            }
+        }
+    }
+
+    /*
+    Collects every entry below this point, including this one, that matches the search,
+    as its path and its value, e.g. `{ "path": "ports/0/localPort", "value": 8080 }`.
+    Array members have no key, so they can only be found by their value or their path.
+    */
+    pub fn find(
+        &mut self,
+        search: &Search,
+        key: Option<&str>,
+        path: &mut Vec<String>,
+        found: &mut Vec<Json>,
+    ) -> Result<()> {
+        if self.matches(search, key, path)? {
+            let value = self.to_value()?;
+            found.push(json!({ "path": key_path::join(path), "value": value }));
+        }
+
+        self.find_in_children(search, path, found)
+    }
+
+    fn find_in_children(
+        &mut self,
+        search: &Search,
+        path: &mut Vec<String>,
+        found: &mut Vec<Json>,
+    ) -> Result<()> {
+        match self {
+            At::Array(arr) => {
+                for (index, val) in arr.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    At::Value(val).find(search, None, path, found)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::ArrayOfTables(aar) => {
+                for (index, table) in aar.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    At::Table(table).find(search, None, path, found)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::Item(item) => match item {
+                Item::None => Ok(()),
+                Item::Value(value) => At::Value(value).find_in_children(search, path, found),
+                Item::ArrayOfTables(aar) => {
+                    At::ArrayOfTables(aar).find_in_children(search, path, found)
+                }
+                Item::Table(table) => At::Table(table).find_in_children(search, path, found),
+            },
+            At::Table(table) => {
+                for (k, i) in table.iter_mut() {
+                    path.push(k.get().to_string());
+                    At::Item(i).find(search, Some(k.get()), path, found)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::Value(Value::Array(arr)) => At::Array(arr).find_in_children(search, path, found),
+            At::Value(Value::InlineTable(table)) => {
+                for (k, v) in table.iter_mut() {
+                    path.push(k.get().to_string());
+                    At::Value(v).find(search, Some(k.get()), path, found)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            At::Value(_) => Ok(()),
+        }
+    }
+
+    fn matches(&mut self, search: &Search, key: Option<&str>, path: &[String]) -> Result<bool> {
+        if let Some(pattern) = &search.key {
+            let matched = if pattern.as_str().contains('/') {
+                let options = MatchOptions {
+                    require_literal_separator: true,
+                    ..MatchOptions::new()
+                };
+                pattern.matches_with(&key_path::join(path), options)
+            } else {
+                key.is_some_and(|key| pattern.matches(key))
+            };
+            if !matched {
+                return Ok(false);
+            }
+        }
+
+        if let Some(regex) = &search.value {
+            let text = match self.kind() {
+                "string" | "integer" | "float" | "boolean" | "datetime" => {
+                    match self.to_value()? {
+                        Json::String(s) => s,
+                        other => other.to_string(),
+                    }
+                }
+                _ => return Ok(false),
+            };
+            return Ok(regex.is_match(&text));
+        }
+
+        Ok(true)
+    }
+
+    pub fn keys(&mut self) -> Result<Json> {
+        let keys: Vec<&str> = match self {
+            At::Table(table) => table.iter().map(|(k, _)| k).collect(),
+            At::Item(Item::Table(table)) => table.iter().map(|(k, _)| k).collect(),
+            At::Value(Value::InlineTable(table)) => table.iter().map(|(k, _)| k).collect(),
+            At::Item(Item::Value(Value::InlineTable(table))) => {
+                table.iter().map(|(k, _)| k).collect()
+            }
+            _ => return Err(anyhow!("Unable to list keys of {}", self.kind())),
+        };
+        Ok(Json::from(keys))
+    }
+
+    pub fn len(&mut self) -> Result<Json> {
+        let len = match self {
+            At::Array(arr) => arr.len(),
+            At::Value(Value::Array(arr)) => arr.len(),
+            At::Item(Item::Value(Value::Array(arr))) => arr.len(),
+            At::ArrayOfTables(aar) => aar.len(),
+            At::Item(Item::ArrayOfTables(aar)) => aar.len(),
+            _ => return Err(anyhow!("Unable to get the length of {}", self.kind())),
+        };
+        Ok(Json::from(len))
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            At::Array(_) => "array",
+            At::ArrayOfTables(_) => "array_of_tables",
+            At::Item(item) => match item {
+                Item::None => "none",
+                Item::Value(value) => value_kind(value),
+                Item::ArrayOfTables(_) => "array_of_tables",
+                Item::Table(_) => "table",
+            },
+            At::Table(_) => "table",
+            At::Value(value) => value_kind(value),
+        }
+    }
+}
+
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        Value::Boolean(_) => "boolean",
+        Value::Datetime(_) => "datetime",
+        Value::Array(_) => "array",
+        Value::InlineTable(_) => "inline_table",
+    }
+}
+
+// puts the key in front of the description of a child node
+fn keyed(key: &str, node: Json) -> Json {
+    let mut keyed = Map::new();
+    keyed.insert("key".to_string(), Json::String(key.to_string()));
+    if let Json::Object(fields) = node {
+        keyed.extend(fields);
+    }
+    Json::Object(keyed)
+}
+
+#[cfg(test)]
+mod traversal_tests {
+    use super::*;
+
+    const DOTREPLIT_CONTENT: &str = r#"run = ["python3", "main.py"]
+nix.channel = "stable-24_05"
+
+[deployment]
+build = { cmd = "make" }
+
+[tool.uv]
+index = 1
+
+[[ports]]
+localPort = 8080
+"#;
+
+    #[test]
+    fn describe_document() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let description = traverse(TraverseOps::Describe, &mut doc, "")
+            .unwrap()
+            .unwrap();
+
+        let expected = json!({
+            "kind": "table", "implicit": false, "dotted": false, "position": null, "children": [
+                { "key": "run", "kind": "array", "items": [{ "kind": "string" }, { "kind": "string" }] },
+                { "key": "nix", "kind": "table", "implicit": true, "dotted": true, "position": null, "children": [
+                    { "key": "channel", "kind": "string" },
+                ] },
+                { "key": "deployment", "kind": "table", "implicit": false, "dotted": false, "position": 1, "children": [
+                    { "key": "build", "kind": "inline_table", "children": [{ "key": "cmd", "kind": "string" }] },
+                ] },
+                { "key": "tool", "kind": "table", "implicit": true, "dotted": false, "position": null, "children": [
+                    { "key": "uv", "kind": "table", "implicit": false, "dotted": false, "position": 2, "children": [
+                        { "key": "index", "kind": "integer" },
+                    ] },
+                ] },
+                { "key": "ports", "kind": "array_of_tables", "items": [
+                    { "kind": "table", "implicit": false, "dotted": false, "position": 3, "children": [
+                        { "key": "localPort", "kind": "integer" },
+                    ] },
+                ] },
+            ]
+        });
+        assert_eq!(description, expected);
+    }
+
+    #[test]
+    fn describe_subtree() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let description = traverse(TraverseOps::Describe, &mut doc, "deployment/build")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            description,
+            json!({ "kind": "inline_table", "children": [{ "key": "cmd", "kind": "string" }] })
+        );
+    }
+
+    #[test]
+    fn get_unquotes_keys() {
+        let mut doc = "[env]\n\"a.b\" = 1\n'c d' = { \"e\\\"f\" = 2 }\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        let env = traverse(TraverseOps::Get, &mut doc, "env")
+            .unwrap()
+            .unwrap();
+        assert_eq!(env, json!({ "a.b": 1, "c d": { "e\"f": 2 } }));
+    }
+
+    #[test]
+    fn keys_in_document_order() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let keys = traverse(TraverseOps::Keys, &mut doc, "").unwrap().unwrap();
+        assert_eq!(keys, json!(["run", "nix", "deployment", "tool", "ports"]));
+
+        let keys = traverse(TraverseOps::Keys, &mut doc, "deployment/build")
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys, json!(["cmd"]));
+
+        assert!(traverse(TraverseOps::Keys, &mut doc, "run").is_err());
+    }
+
+    #[test]
+    fn len_of_arrays() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let len = traverse(TraverseOps::Len, &mut doc, "run")
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, json!(2));
+
+        let len = traverse(TraverseOps::Len, &mut doc, "ports")
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, json!(1));
+
+        assert!(traverse(TraverseOps::Len, &mut doc, "deployment").is_err());
+    }
+
+    #[test]
+    fn type_of_items() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        for (path, kind) in [
+            ("run", "array"),
+            ("run/0", "string"),
+            ("nix", "table"),
+            ("deployment/build", "inline_table"),
+            ("ports", "array_of_tables"),
+            ("ports/0", "table"),
+            ("ports/0/localPort", "integer"),
+        ] {
+            let found = traverse(TraverseOps::Type, &mut doc, path)
+                .unwrap()
+                .unwrap();
+            assert_eq!(found, json!(kind), "type of {}", path);
+        }
+    }
+
+    #[test]
+    fn flatten_document() {
+        let mut doc = r#"
+run = ["python3", "main.py"]
+env = { "PATH/extra" = "/bin" }
+
+[nix]
+channel = "stable-24_05"
+packages = []
+
+[[ports]]
+localPort = 8080
+"#
+        .parse::<DocumentMut>()
+        .unwrap();
+        let leaves = traverse(TraverseOps::Flatten, &mut doc, "")
+            .unwrap()
+            .unwrap();
+
+        let expected = json!([
+            { "path": "run/0", "value": "python3" },
+            { "path": "run/1", "value": "main.py" },
+            { "path": "env/PATH~1extra", "value": "/bin" },
+            { "path": "nix/channel", "value": "stable-24_05" },
+            { "path": "nix/packages", "value": [] },
+            { "path": "ports/0/localPort", "value": 8080 },
+        ]);
+        assert_eq!(leaves, expected);
+    }
+
+    #[test]
+    fn flatten_subtree() {
+        let mut doc = DOTREPLIT_CONTENT.parse::<DocumentMut>().unwrap();
+        let leaves = traverse(TraverseOps::Flatten, &mut doc, "deployment")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            leaves,
+            json!([{ "path": "deployment/build/cmd", "value": "make" }])
+        );
+    }
 }
@@ -0,0 +1,505 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{from_str, Value as JValue};
+use toml_edit::DocumentMut;
+
+use crate::differ::{diff, lookup};
+use crate::key_path;
+use crate::traversal::to_json;
+use crate::{AddOp, OpKind};
+
+/*
+Checks an edited document against what its ops were meant to do. The JSON of
+the document before the batch serves as a model, every op is applied to the
+model the way it is supposed to work, and the edited document, parsed again
+from its text, has to come out as the same JSON. This catches added values
+that round-trip to different data, removed paths that are still there and
+changes outside of the paths the batch touched.
+
+Ops whose effect can't be worked out from the request alone, like `set_raw`
+or `merge`, take over what the document has at the paths they touched.
+*/
+pub struct Model {
+    json: JValue,
+}
+
+impl Model {
+    pub fn new(doc: &mut DocumentMut) -> Result<Model> {
+        Ok(Model {
+            json: to_json(doc)?,
+        })
+    }
+
+    // `output` is what the op returned and `doc` the document after the op
+    pub fn apply(&mut self, op: &OpKind, output: &JValue, doc: &mut DocumentMut) -> Result<()> {
+        match op {
+            OpKind::Add(op) => self.add(op, doc)?,
+            OpKind::Remove { path } => self.remove(&key_path::split(path)),
+            OpKind::Replace { path, value } => {
+                let path = key_path::split(path);
+                if !self.set(&path, from_str(value)?) {
+                    self.trust(doc, &path)?;
+                }
+            }
+            OpKind::Sync { path, value } => {
+                let path = match path.as_deref() {
+                    None | Some("") => vec![],
+                    Some(path) => key_path::split(path),
+                };
+                // null stands for a missing item, the same as in the patch sync applies
+                if !self.set(&path, without_nulls(from_str(value)?)) {
+                    self.trust(doc, &path)?;
+                }
+            }
+            OpKind::SetRaw { path, .. } if path.is_empty() => self.json = to_json(doc)?,
+            OpKind::SetRaw { path, .. } => self.trust(doc, &key_path::split(path))?,
+            OpKind::Merge { .. } => {
+                let applied = output["applied"].as_array().cloned().unwrap_or_default();
+                for op in applied {
+                    let path = op["path"].as_str().unwrap_or_default();
+                    self.trust(doc, &key_path::split(path))?;
+                }
+            }
+            OpKind::Restore { .. } => self.json = to_json(doc)?,
+            // everything else only reads
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Parses the text of the document again and compares it to the model.
+    // The error lists every path where the two differ.
+    pub fn verify(&self, doc: &DocumentMut) -> Result<()> {
+        let mut reparsed = doc
+            .to_string()
+            .parse::<DocumentMut>()
+            .context("the edited document does not parse anymore")?;
+        let actual = to_json(&mut reparsed)?;
+
+        let mismatches = diff(&self.json, &actual, &[])
+            .iter()
+            .map(|op| {
+                format!(
+                    "{} (expected {}, found {})",
+                    key_path::join(op.path()),
+                    lookup(&self.json, op.path()),
+                    lookup(&actual, op.path())
+                )
+            })
+            .collect::<Vec<_>>();
+        if !mismatches.is_empty() {
+            bail!(
+                "refusing to write, the edited document differs from the requested changes at: {}",
+                mismatches.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, op: &AddOp, doc: &mut DocumentMut) -> Result<()> {
+        let value: JValue = from_str(op.value.as_deref().unwrap_or("null"))?;
+        let path = op.dotted_path.as_ref().or(op.path.as_ref());
+
+        let thpath = match &op.table_header_path {
+            Some(thpath) => thpath,
+            // a plain path is taken literally, a trailing "[]" or "[[]]" included
+            None => {
+                if let Some(path) = path {
+                    let path = key_path::split(path);
+                    if !self.set(&path, value) {
+                        self.trust(doc, &path)?;
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        // "[[]]" appends a table to the array of tables at the header path,
+        // which the dotted path then goes into, and "[]" appends a value to
+        // the array at the dotted path
+        let mut full_path = key_path::split(thpath);
+        if full_path.last().is_some_and(|key| key == "[[]]") {
+            full_path.pop();
+            let index = match lookup(&self.json, &full_path) {
+                JValue::Array(array) => array.len(),
+                _ => 0,
+            };
+            full_path.push(index.to_string());
+        }
+        let mut append = false;
+        if let Some(path) = path {
+            full_path.extend(key_path::split(path));
+            if full_path.last().is_some_and(|key| key == "[]") {
+                full_path.pop();
+                append = true;
+            }
+        }
+        self.simulate(&full_path, value, append, doc)
+    }
+
+    // sets or appends the value, or takes over what the document has there if the model can't
+    fn simulate(
+        &mut self,
+        path: &[String],
+        value: JValue,
+        append: bool,
+        doc: &mut DocumentMut,
+    ) -> Result<()> {
+        let simulated = if append {
+            self.append(path, value)
+        } else {
+            self.set(path, value)
+        };
+        if !simulated {
+            self.trust(doc, path)?;
+        }
+        Ok(())
+    }
+
+    // Sets the value at a path the way `add` does: missing tables on the way
+    // are created and an index past the end of an array appends to it.
+    // Returns false if the path can't be followed.
+    fn set(&mut self, path: &[String], value: JValue) -> bool {
+        let (last, parents) = match path.split_last() {
+            Some(split) => split,
+            None => {
+                self.json = value;
+                return true;
+            }
+        };
+
+        match self.container(parents, last, true) {
+            Some(JValue::Object(map)) => {
+                map.insert(last.clone(), value);
+                true
+            }
+            Some(JValue::Array(array)) => match last.parse::<usize>() {
+                Ok(index) if index < array.len() => {
+                    array[index] = value;
+                    true
+                }
+                Ok(_) => {
+                    array.push(value);
+                    true
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn append(&mut self, path: &[String], value: JValue) -> bool {
+        let (last, parents) = match path.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+
+        match self.container(parents, last, true) {
+            Some(JValue::Object(map)) => match map
+                .entry(last.clone())
+                .or_insert_with(|| JValue::Array(vec![]))
+            {
+                JValue::Array(array) => {
+                    array.push(value);
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn remove(&mut self, path: &[String]) {
+        let (last, parents) = match path.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+
+        match self.container(parents, last, false) {
+            Some(JValue::Object(map)) => {
+                map.remove(last);
+            }
+            Some(JValue::Array(array)) => {
+                if let Some(index) = last.parse::<usize>().ok().filter(|i| *i < array.len()) {
+                    array.remove(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Takes over whatever the document has at the path. Where the model can't
+    // follow the path, the smallest part of the document around it that it
+    // can is taken over instead, so that the rest is still checked.
+    fn trust(&mut self, doc: &mut DocumentMut, path: &[String]) -> Result<()> {
+        let actual = to_json(doc)?;
+        for end in (0..=path.len()).rev() {
+            let trusted = match lookup(&actual, &path[..end]) {
+                JValue::Null if end == path.len() => {
+                    self.remove(path);
+                    true
+                }
+                JValue::Null => false,
+                value => self.set(&path[..end], value),
+            };
+            if trusted {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Walks down to the container at a path. If asked to, missing containers
+    // are created on the way like `add` does: as an array if they are indexed
+    // into, as a table otherwise.
+    fn container(&mut self, path: &[String], last: &str, create: bool) -> Option<&mut JValue> {
+        let mut current = &mut self.json;
+        for (depth, segment) in path.iter().enumerate() {
+            current = match current {
+                JValue::Object(map) => {
+                    if create && !map.contains_key(segment) {
+                        let next = path.get(depth + 1).map_or(last, |next| next.as_str());
+                        let container = match next.parse::<usize>() {
+                            Ok(_) => JValue::Array(vec![]),
+                            Err(_) => JValue::Object(Default::default()),
+                        };
+                        map.insert(segment.clone(), container);
+                    }
+                    map.get_mut(segment)?
+                }
+                JValue::Array(array) => {
+                    let index = segment.parse::<usize>().ok()?;
+                    if create && index == array.len() {
+                        array.push(JValue::Object(Default::default()));
+                    }
+                    array.get_mut(index)?
+                }
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+}
+
+fn without_nulls(value: JValue) -> JValue {
+    match value {
+        JValue::Object(map) => JValue::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        JValue::Array(array) => JValue::Array(array.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod verifier_tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::apply_batch;
+
+    // runs the ops through `apply_batch`, which verifies them, like a request would
+    fn batch(source: &str, ops: JValue) -> Result<String> {
+        let mut doc = source.parse::<DocumentMut>().unwrap();
+        let raw = ops.as_array().unwrap().clone();
+        let json = serde_json::from_value(ops).unwrap();
+        apply_batch(&mut doc, json, &raw, None, None)?;
+        Ok(doc.to_string())
+    }
+
+    fn model(source: &str) -> (Model, DocumentMut) {
+        let mut doc = source.parse::<DocumentMut>().unwrap();
+        (Model::new(&mut doc).unwrap(), doc)
+    }
+
+    #[test]
+    fn verify_add_and_remove() {
+        let (mut model, mut doc) =
+            model("run = \"python3 main.py\"\nhidden = []\n[nix]\nchannel = \"stable-23_05\"\n");
+        let ops: Vec<OpKind> = serde_json::from_value(json!([
+            { "op": "add", "path": "hidden/0", "value": "\".cache\"" },
+            { "op": "add", "table_header_path": "ports/[[]]", "value": "{\"localPort\": 8080}" },
+            { "op": "remove", "path": "nix/channel" },
+        ]))
+        .unwrap();
+
+        for op in ops {
+            // the document is edited by hand here, the way the ops should have done it
+            model.apply(&op, &JValue::Null, &mut doc).unwrap();
+        }
+        let edited = "run = \"python3 main.py\"\nhidden = [\".cache\"]\n[nix]\n\n[[ports]]\nlocalPort = 8080\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        model.verify(&edited).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_unexpected_changes() {
+        let (mut model, mut doc) = model("run = \"python3 main.py\"\nentrypoint = \"main.py\"\n");
+        let op: OpKind = serde_json::from_value(
+            json!({ "op": "add", "path": "run", "value": "\"python3 app.py\"" }),
+        )
+        .unwrap();
+        model.apply(&op, &JValue::Null, &mut doc).unwrap();
+
+        let edited = "run = \"python3 app.py\"\nentrypoint = \"app.py\"\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        let error = model.verify(&edited).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "refusing to write, the edited document differs from the requested changes at: entrypoint (expected \"main.py\", found \"app.py\")"
+        );
+    }
+
+    #[test]
+    fn verify_adds() {
+        let add = |source: &str, op: JValue| batch(source, json!([op])).unwrap();
+
+        // a trailing "[]" or "[[]]" of a plain path is a key like any other
+        assert_eq!(
+            add("", json!({ "op": "add", "path": "foo/[]", "value": "1" })),
+            "[foo]\n\"[]\" = 1\n"
+        );
+        assert_eq!(
+            add(
+                "",
+                json!({ "op": "add", "dotted_path": "foo/[[]]", "value": "1" })
+            ),
+            "[foo]\n\"[[]]\" = 1\n"
+        );
+        // an index past the end of an array of tables adds a table
+        assert_eq!(
+            add(
+                "[[ports]]\nlocalPort = 1\n",
+                json!({ "op": "add", "path": "ports/1/localPort", "value": "2" })
+            ),
+            "[[ports]]\nlocalPort = 1\n\n[[ports]]\nlocalPort = 2\n"
+        );
+
+        assert_eq!(
+            add(
+                "",
+                json!({
+                    "op": "add",
+                    "table_header_path": "tool/uv/index/[[]]",
+                    "value": "{\"key\": \"a\"}",
+                })
+            ),
+            "[[tool.uv.index]]\nkey = \"a\"\n"
+        );
+        assert_eq!(
+            add(
+                "",
+                json!({
+                    "op": "add",
+                    "table_header_path": "tool/uv",
+                    "dotted_path": "sources/torch/[]",
+                    "value": "1",
+                })
+            ),
+            "[tool.uv]\nsources.torch = [1]\n"
+        );
+        // "[[]]" with a dotted path appends a table with the value at that path
+        assert_eq!(
+            add(
+                "",
+                json!({
+                    "op": "add",
+                    "table_header_path": "pkgs/[[]]",
+                    "dotted_path": "name",
+                    "value": "\"a\"",
+                })
+            ),
+            "[[pkgs]]\nname = \"a\"\n"
+        );
+        assert_eq!(
+            add(
+                "[[pkgs]]\nname = \"a\"\n",
+                json!({
+                    "op": "add",
+                    "table_header_path": "pkgs/[[]]",
+                    "dotted_path": "name",
+                    "value": "\"b\"",
+                })
+            ),
+            "[[pkgs]]\nname = \"a\"\n\n[[pkgs]]\nname = \"b\"\n"
+        );
+    }
+
+    #[test]
+    fn verify_rejects_misplaced_tables() {
+        let op: OpKind = serde_json::from_value(json!({
+            "op": "add",
+            "table_header_path": "pkgs/[[]]",
+            "dotted_path": "name",
+            "value": "\"b\"",
+        }))
+        .unwrap();
+
+        // a plain table instead of an array of tables
+        let (mut empty, mut doc) = model("");
+        empty.apply(&op, &JValue::Null, &mut doc).unwrap();
+        let edited = "[pkgs]\nname = \"b\"\n".parse::<DocumentMut>().unwrap();
+        assert!(empty.verify(&edited).is_err());
+
+        // a table appended without the dotted path
+        let (mut existing, mut doc) = model("[[pkgs]]\nname = \"a\"\n");
+        existing.apply(&op, &JValue::Null, &mut doc).unwrap();
+        let edited = "[[pkgs]]\nname = \"a\"\n\n[[pkgs]]\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        assert!(existing.verify(&edited).is_err());
+    }
+
+    #[test]
+    fn verify_trusts_only_the_touched_part() {
+        // the model can't follow a key into an array, so it takes over `hidden`
+        // from the document, but not the rest of it
+        let (mut model, _) = model("run = \"python3 main.py\"\nhidden = []\n");
+        let op: OpKind =
+            serde_json::from_value(json!({ "op": "replace", "path": "hidden/x", "value": "1" }))
+                .unwrap();
+        let mut edited = "run = \"bash\"\n\n[hidden]\nx = 1\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        model.apply(&op, &JValue::Null, &mut edited).unwrap();
+        assert_eq!(
+            model.verify(&edited).unwrap_err().to_string(),
+            "refusing to write, the edited document differs from the requested changes at: run (expected \"python3 main.py\", found \"bash\")"
+        );
+    }
+
+    #[test]
+    fn verify_every_op() {
+        let source = "run = \"python3 main.py\" # start\nhidden = [\".cache\"]\n\n\
+                      [nix]\nchannel = \"stable-23_05\"\n";
+        let ops = json!([
+            { "op": "get", "path": "run" },
+            { "op": "describe" },
+            { "op": "keys" },
+            { "op": "len", "path": "hidden" },
+            { "op": "type", "path": "nix" },
+            { "op": "flatten" },
+            { "op": "find", "key": "chan*" },
+            { "op": "get_raw", "path": "nix" },
+            { "op": "diff", "value": "" },
+            { "op": "replace", "path": "run", "value": "\"python3 app.py\"" },
+            { "op": "add", "path": "hidden/1", "value": "\"venv\"" },
+            { "op": "remove", "path": "hidden/0" },
+            { "op": "set_raw", "path": "nix/channel", "value": "\"stable-24_05\" # pinned" },
+            { "op": "sync", "path": "env", "value": "{\"PORT\": \"3000\"}" },
+            { "op": "merge", "base": "", "value": "entrypoint = \"app.py\"" },
+        ]);
+        assert_eq!(
+            batch(source, ops).unwrap(),
+            "run = \"python3 app.py\" # start\nhidden = [ \"venv\"]\nentrypoint = \"app.py\"\n\n\
+             [nix]\nchannel = \"stable-24_05\" # pinned\n\n[env]\nPORT = \"3000\"\n"
+        );
+
+        let whole = json!([{ "op": "set_raw", "path": "", "value": "run = \"bash\"\n" }]);
+        assert_eq!(batch(source, whole).unwrap(), "run = \"bash\"\n");
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::differ::{diff, PatchOp};
+use crate::framing;
+use crate::target::canonical;
+use crate::traversal::parse_json;
+use crate::{lock, Protocol, Session};
+
+/*
+Watches the file that requests on stdin edit by default, so that clients
+learn about changes that someone else made to it, like a user typing in an
+editor, without polling with `get`. What the editor writes itself isn't
+reported, since the client that asked for it already knows (see `record`).
+*/
+pub struct Watch {
+    pub file: PathBuf,
+    pub interval: Duration,
+    // the contents before and after the editor's last write, until the next poll
+    written: Mutex<Option<(String, String)>>,
+}
+
+impl Watch {
+    pub fn new(file: &Path, interval: Duration) -> Watch {
+        Watch {
+            file: file.to_path_buf(),
+            interval,
+            written: Mutex::new(None),
+        }
+    }
+
+    // Called with the lock of the file held, see `Session::histories`, so
+    // that a poll never sees a write that isn't recorded yet.
+    pub fn record(&self, file: &Path, before: &str, after: &str) {
+        if file != self.file && canonical(file) != canonical(&self.file) {
+            return;
+        }
+        let mut written = lock(&self.written);
+        // a write right after another one is the editor's as well
+        let before = match written.take() {
+            Some((first, last)) if last == before => first,
+            _ => before.to_string(),
+        };
+        *written = Some((before, after.to_string()));
+    }
+}
+
+// what the watcher has seen of the file so far
+pub struct Watcher {
+    contents: String,
+    json: Value,
+}
+
+impl Watcher {
+    pub fn new(watch: &Watch) -> Watcher {
+        let contents = read(&watch.file);
+        let json = parse_json(&contents).unwrap_or_else(|| Value::Object(Map::new()));
+        Watcher { contents, json }
+    }
+
+    /*
+    Looks at the file once, and writes a notification with the patch from what
+    it was to what it is now, if someone other than the editor changed it.
+    A file that doesn't parse isn't reported until it does again, since it is
+    most likely being typed into. A file that was removed is reported as empty.
+    */
+    pub fn poll(
+        &mut self,
+        watch: &Watch,
+        writer: &mut impl Write,
+        session: &Session,
+    ) -> Result<()> {
+        if read(&watch.file) == self.contents {
+            return Ok(());
+        }
+
+        let history = session.history(&watch.file);
+        let _locked = lock(&history);
+        let contents = read(&watch.file);
+        // what the file was when the editor read it, if it is what the editor wrote
+        let external = match lock(&watch.written).take() {
+            Some((before, after)) if after == contents => before,
+            _ => contents.clone(),
+        };
+
+        if let Some(external) = parse_json(&external) {
+            let patch = diff(&self.json, &external, &[]);
+            if !patch.is_empty() {
+                notify(writer, &watch.file, &patch, session)?;
+            }
+            self.json = external;
+        }
+        if let Some(json) = parse_json(&contents) {
+            self.json = json;
+        }
+        self.contents = contents;
+        Ok(())
+    }
+}
+
+// Polls the file until `stop` is set.
+pub fn run(
+    watch: &Watch,
+    stop: &AtomicBool,
+    writer: &mut impl Write,
+    session: &Session,
+) -> Result<()> {
+    let mut watcher = Watcher::new(watch);
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(watch.interval);
+        watcher.poll(watch, writer, session)?;
+    }
+    Ok(())
+}
+
+fn notify(
+    writer: &mut impl Write,
+    file: &Path,
+    patch: &[PatchOp],
+    session: &Session,
+) -> Result<()> {
+    let file = file.to_string_lossy();
+    let patch = patch.iter().map(PatchOp::to_json).collect::<Vec<_>>();
+    let notification = match session.protocol {
+        Protocol::Lines => json!({ "event": "changed", "file": file, "patch": patch }),
+        Protocol::Jsonrpc => json!({
+            "jsonrpc": "2.0",
+            "method": "toml/didChange",
+            "params": { "file": file, "patch": patch },
+        }),
+    };
+    framing::write(writer, session.framing, &notification.to_string())?;
+    Ok(())
+}
+
+// the contents of the file, which are empty if it doesn't exist
+fn read(file: &Path) -> String {
+    fs::read_to_string(file).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    use crate::do_edits;
+    use crate::journal::Caller;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn watch_external_changes() {
+        let dir = scratch_dir("watch");
+        let file = dir.join(".replit");
+        fs::write(&file, "run = \"a\"\n").unwrap();
+
+        let session = Session {
+            watch: Some(Watch::new(&file, Duration::from_millis(10))),
+            ..Session::for_test(&dir)
+        };
+        let watch = session.watch.as_ref().unwrap();
+        let mut watcher = Watcher::new(watch);
+        let mut poll = || {
+            let mut output = vec![];
+            watcher.poll(watch, &mut output, &session).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap()["patch"].clone())
+                .collect::<Vec<_>>()
+        };
+        let edit = |path: &str| {
+            let ops = vec![json!({ "op": "add", "path": path, "value": "true" })];
+            do_edits(&file, ops, false, false, &Caller::default(), &session).unwrap();
+        };
+
+        assert!(poll().is_empty());
+
+        fs::write(&file, "run = \"b\"\n# comments don't count\n").unwrap();
+        assert_eq!(
+            poll(),
+            [json!([{ "op": "replace", "path": "run", "value": "\"b\"" }])]
+        );
+
+        // the editor's own writes aren't reported
+        edit("audio");
+        edit("disableGuessImports");
+        assert!(poll().is_empty());
+
+        // nor is a document that is being typed into, until it parses again
+        fs::write(&file, "run = \"b\"\naudio = \n").unwrap();
+        assert!(poll().is_empty());
+        fs::write(&file, "run = \"c\"\naudio = true\n").unwrap();
+        assert_eq!(
+            poll(),
+            [json!([
+                { "op": "remove", "path": "disableGuessImports" },
+                { "op": "replace", "path": "run", "value": "\"c\"" },
+            ])]
+        );
+
+        // a change that the editor read before writing is still reported
+        fs::write(&file, "run = \"d\"\naudio = true\n").unwrap();
+        edit("hidden");
+        assert_eq!(
+            poll(),
+            [json!([{ "op": "replace", "path": "run", "value": "\"d\"" }])]
+        );
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(poll()[0].as_array().unwrap().len(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}